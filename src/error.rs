@@ -0,0 +1,22 @@
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter};
+use crate::Id;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HeapError<I = Id> {
+    DuplicateId(I),
+    InconsistentIndexMap,
+    NotHeapOrdered,
+}
+
+impl<I: Debug> Display for HeapError<I> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HeapError::DuplicateId(id) => write!(f, "duplicate id {:?}", id),
+            HeapError::InconsistentIndexMap => write!(f, "index map does not match the backing array"),
+            HeapError::NotHeapOrdered => write!(f, "backing array is not heap-ordered"),
+        }
+    }
+}
+
+impl<I: Debug> Error for HeapError<I> {}
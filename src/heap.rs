@@ -1,14 +1,47 @@
-use std::collections::HashMap;
-use std::fmt::Debug;
+use std::collections::hash_map::RandomState;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, HashSet, TryReserveError};
+use std::convert::TryFrom;
+use std::fmt::{Debug, Formatter};
+use std::mem;
+use std::hash::{BuildHasher, Hash};
+use std::iter::FromIterator;
 use std::ops::{Deref, DerefMut, Index};
-use crate::{Id, Key};
+use std::sync::Arc;
+#[cfg(feature = "rand")]
+use rand::Rng;
+#[cfg(feature = "rayon")]
+use rayon::slice::ParallelSliceMut;
+#[cfg(feature = "stats")]
+use crate::stats::{StatCounters, Stats};
+use crate::trace::op_span;
+use crate::{HeapError, Id, Key};
 
 pub trait HeapItem: Debug + Clone {
+    type Id: Eq + Hash + Clone + Debug;
+
     fn key(&self) -> Key;
-    fn id(&self) -> Id;
+    fn id(&self) -> Self::Id;
+
+    /// Called whenever the heap places this element at `new_index` of its backing array, for
+    /// elements that cache their own position. Elements handed over through `from_parts` are
+    /// assumed to already know where they are.
+    fn on_move(&mut self, _new_index: usize) {}
+}
+
+/// Items whose key can be changed in place, which enables `Heap::change_key`.
+pub trait SetKey: HeapItem {
+    fn set_key(&mut self, key: Key);
+}
+
+/// Items whose id can be reassigned in place, which enables `Heap::compact_ids`.
+pub trait SetId: HeapItem {
+    fn set_id(&mut self, id: Self::Id);
 }
 
 impl<T: Clone + Into<Key> + Into<Id> + Debug> HeapItem for T {
+    type Id = Id;
+
     fn key(&self) -> Key {
         self.clone().into()
     }
@@ -17,329 +50,3163 @@ impl<T: Clone + Into<Key> + Into<Id> + Debug> HeapItem for T {
     }
 }
 
-#[derive(Debug)]
-pub struct Heap<T: HeapItem> {
-    heap: Vec<T>,
-    index_map: HashMap<Id, usize>,
-}
+/// Orders elements so that `Ordering::Greater` means "closer to the root".
+type Comparator<T> = Arc<dyn Fn(&T, &T) -> Ordering + Send + Sync>;
 
-impl<T: HeapItem> Index<Id> for Heap<T> {
-    type Output = T;
+type PopObserver<T> = Box<dyn FnMut(&T) + Send + Sync>;
 
-    fn index(&self, index: Id) -> &Self::Output {
-        &self.heap[*self.index_map.get(&index).unwrap()]
+/// Derives an element's id in place of `HeapItem::id`.
+type IdFn<T> = Arc<dyn Fn(&T) -> <T as HeapItem>::Id + Send + Sync>;
+
+fn id_with<T: HeapItem>(id_fn: &Option<IdFn<T>>, item: &T) -> T::Id {
+    match id_fn {
+        None => item.id(),
+        Some(id_fn) => id_fn(item),
     }
 }
 
-impl<T: HeapItem> Heap<T> {
-    pub fn heapify(items: Vec<T>) -> Self {
-        let item_count = items.len();
-        let index_map = items.iter().enumerate().map(|(i, val)| (val.id(), i)).collect();
-        let mut result = Heap {
-            heap: items,
-            index_map,
-        };
-        let mut view = result.get_mut_view();
-        for ix in (0..(item_count >> 1)).rev() {
-            view.index = ix;
-            view.sift_down()
+/// An element order other than plain key order.
+enum HeapOrder<T: HeapItem> {
+    /// Set by `with_comparator` or `replace_comparator`.
+    Custom(Comparator<T>),
+    /// Set by `stabilize_by_id`: key first, then ascending id. The heap's `id_fn` is passed in
+    /// at each comparison rather than captured, so items need not be `'static`.
+    KeyThenId(fn(&Option<IdFn<T>>, &T, &T) -> Ordering),
+}
+
+impl<T: HeapItem> Clone for HeapOrder<T> {
+    fn clone(&self) -> Self {
+        match self {
+            HeapOrder::Custom(comparator) => HeapOrder::Custom(comparator.clone()),
+            HeapOrder::KeyThenId(compare) => HeapOrder::KeyThenId(*compare),
         }
-        result
     }
+}
 
-    pub fn len(&self) -> usize {
-        self.heap.len()
+fn compare_with<T: HeapItem>(comparator: &Option<HeapOrder<T>>, id_fn: &Option<IdFn<T>>, a: &T, b: &T) -> Ordering {
+    match comparator {
+        None => a.key().cmp(&b.key()),
+        Some(HeapOrder::Custom(cmp)) => cmp(a, b),
+        Some(HeapOrder::KeyThenId(cmp)) => cmp(id_fn, a, b),
     }
+}
 
-    pub fn is_empty(&self) -> bool {
-        self.heap.is_empty()
-    }
+fn compare_key_then_id<T: HeapItem>(id_fn: &Option<IdFn<T>>, a: &T, b: &T) -> Ordering
+where
+    T::Id: Ord,
+{
+    a.key().cmp(&b.key()).then_with(|| id_with(id_fn, b).cmp(&id_with(id_fn, a)))
+}
 
-    pub fn push(&mut self, value: T) {
-        self.index_map.insert(value.id(), self.heap.len());
-        self.heap.push(value);
-        self.get_mut_view_at(self.heap.len() - 1).sift_up();
-    }
+fn is_heap_by<T: HeapItem>(slice: &[T], comparator: &Option<HeapOrder<T>>, id_fn: &Option<IdFn<T>>) -> bool {
+    (1..slice.len()).all(|i| compare_with(comparator, id_fn, &slice[i], &slice[(i - 1) >> 1]) != Ordering::Greater)
+}
 
-    pub fn pop(&mut self) -> Option<T> {
-        if self.heap.is_empty() {
-            None
-        } else {
-            let last_index = self.heap.len() - 1;
-            self.get_mut_view().transpose(last_index);
-            let result = self.heap.pop();
-            self.index_map.remove(&result.as_ref().unwrap().id());
-            self.get_mut_view().sift_down();
-            result
+fn index_map_matches<T: HeapItem, S: BuildHasher>(
+    heap: &[T],
+    index_map: &HashMap<T::Id, usize, S>,
+    id_fn: &Option<IdFn<T>>,
+) -> bool {
+    index_map.len() == heap.len()
+        && index_map.iter().all(|(id, &index)| heap.get(index).is_some_and(|item| id_with(id_fn, item) == *id))
+}
+
+/// The part of `index_map_matches` that is cheap to check after a single operation: the lengths
+/// agree, and each in-bounds slot in `slots` is mapped back to by its element's id.
+fn slots_match<T: HeapItem, S: BuildHasher>(
+    heap: &[T],
+    index_map: &HashMap<T::Id, usize, S>,
+    id_fn: &Option<IdFn<T>>,
+    slots: &[usize],
+) -> bool {
+    index_map.len() == heap.len()
+        && slots
+            .iter()
+            .all(|&slot| heap.get(slot).is_none_or(|item| index_map.get(&id_with(id_fn, item)) == Some(&slot)))
+}
+
+/// Checks that every element of `slice` has a key no greater than its parent's, i.e. that the
+/// slice is a valid level-order max-heap.
+pub fn is_heap<T: HeapItem>(slice: &[T]) -> bool {
+    is_heap_by(slice, &None, &None)
+}
+
+/// Rearranges `slice` into a level-order max-heap by key. Only the array is touched, so the
+/// result pairs naturally with `is_heap` or with `Heap::from_parts`.
+pub fn heapify_in_place<T: HeapItem>(slice: &mut [T]) {
+    for start in (0..slice.len() >> 1).rev() {
+        let mut index = start;
+        loop {
+            let left = 2 * index + 1;
+            if left >= slice.len() {
+                break;
+            }
+            let right = left + 1;
+            let max = if right < slice.len() && slice[right].key() > slice[left].key() {
+                right
+            } else {
+                left
+            };
+            if slice[max].key() > slice[index].key() {
+                slice.swap(index, max);
+                index = max;
+            } else {
+                break;
+            }
         }
     }
+}
 
-    fn get_view(&self) -> HeapView<T> {
-        self.get_view_at(0)
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RepairReport {
+    /// `index_map` entries pointing past the end of the backing array.
+    pub dangling_entries: usize,
+    /// `index_map` entries pointing at an element with a different id.
+    pub misdirected_entries: usize,
+    /// Elements whose id had no `index_map` entry at all.
+    pub unmapped_elements: usize,
+    /// Whether the backing array had to be re-heapified.
+    pub reordered: bool,
+}
+
+impl RepairReport {
+    pub fn is_clean(&self) -> bool {
+        *self == RepairReport::default()
     }
+}
 
-    fn get_view_at(&self, index: usize) -> HeapView<T> {
-        HeapView {
-            index,
-            heap: &self.heap,
-            index_map: &self.index_map,
-        }
+/// How the backing array grows once it runs out of room.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum CapacityPolicy {
+    /// Leave growth to `Vec`, which roughly doubles its capacity.
+    #[default]
+    Doubling,
+    /// Grow in whole steps of the given number of elements.
+    Fixed(usize),
+    /// Grow by about 1.6x, trading more frequent reallocation for smaller spikes.
+    GoldenRatio,
+}
+
+/// Which element `Heap::dedup_by_id` keeps out of several sharing an id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DedupKeep {
+    /// The one earliest in the backing array.
+    First,
+    /// The one with the largest key, ties going to the earliest.
+    HighestKey,
+}
+
+#[derive(Debug, Clone)]
+struct MergeCursor {
+    key: Key,
+    source: usize,
+}
+
+impl HeapItem for MergeCursor {
+    type Id = usize;
+
+    fn key(&self) -> Key {
+        self.key
     }
 
-    fn get_mut_view(&mut self) -> HeapViewMut<T> {
-        self.get_mut_view_at(0)
+    fn id(&self) -> usize {
+        self.source
     }
+}
 
-    fn get_mut_view_at(&mut self, index: usize) -> HeapViewMut<T> {
-        HeapViewMut {
-            index,
-            heap: &mut self.heap,
-            index_map: &mut self.index_map,
-        }
+pub struct Heap<T: HeapItem, S = RandomState> {
+    heap: Vec<T>,
+    index_map: HashMap<T::Id, usize, S>,
+    max_len: Option<usize>,
+    peak_len: usize,
+    auto_shrink: bool,
+    capacity_policy: CapacityPolicy,
+    comparator: Option<HeapOrder<T>>,
+    id_fn: Option<IdFn<T>>,
+    pop_observer: Option<PopObserver<T>>,
+    #[cfg(feature = "stats")]
+    stats: StatCounters,
+}
+
+impl<T: HeapItem, S> Debug for Heap<T, S> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Heap")
+            .field("heap", &self.heap)
+            .field("index_map", &self.index_map)
+            .field("max_len", &self.max_len)
+            .field("peak_len", &self.peak_len)
+            .field("auto_shrink", &self.auto_shrink)
+            .field("capacity_policy", &self.capacity_policy)
+            .field("custom_comparator", &self.comparator.is_some())
+            .field("custom_id_fn", &self.id_fn.is_some())
+            .field("pop_observer", &self.pop_observer.is_some())
+            .finish()
     }
+}
 
-    pub fn get_mut(&mut self, id: Id) -> Option<HeapItemRefMut<T>> {
-        let index = *self.index_map.get(&id)?;
-        let original_key = self.heap[index].key();
-        let original_id = self.heap[index].id();
-        Some(HeapItemRefMut {
-            view: self.get_mut_view_at(index),
-            original_key,
-            original_id,
-        })
+impl<T: HeapItem, S: Clone> Clone for Heap<T, S> {
+    fn clone(&self) -> Self {
+        Heap {
+            heap: self.heap.clone(),
+            index_map: self.index_map.clone(),
+            max_len: self.max_len,
+            peak_len: self.peak_len,
+            auto_shrink: self.auto_shrink,
+            capacity_policy: self.capacity_policy,
+            comparator: self.comparator.clone(),
+            id_fn: self.id_fn.clone(),
+            pop_observer: None,
+            #[cfg(feature = "stats")]
+            stats: self.stats.clone(),
+        }
     }
+}
 
-    pub fn get(&self, id: Id) -> Option<&T> {
-        Some(&self.heap[*self.index_map.get(&id)?])
+impl<T: HeapItem, S: BuildHasher> Index<T::Id> for Heap<T, S> {
+    type Output = T;
+
+    fn index(&self, index: T::Id) -> &Self::Output {
+        &self.heap[*self.index_map.get(&index).unwrap()]
     }
 }
 
+impl<T: HeapItem, S: BuildHasher + Default> FromIterator<T> for Heap<T, S> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        let iter = iter.into_iter();
+        let mut items = Vec::with_capacity(iter.size_hint().0);
+        items.extend(iter);
+        Heap::heapify_with_hasher(items, S::default())
+    }
+}
 
-#[derive(PartialEq, Debug)]
-struct HeapView<'a, T: HeapItem> {
-    index: usize,
-    heap: &'a Vec<T>,
-    index_map: &'a HashMap<Id, usize>,
+impl<T: HeapItem, S: BuildHasher> Extend<T> for Heap<T, S> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        let iter = iter.into_iter();
+        let mut items = Vec::with_capacity(iter.size_hint().0);
+        items.extend(iter);
+        self.append_batch(items);
+    }
 }
 
-impl<'a, T: HeapItem> HeapView<'a, T> {
-    fn parent(&self) -> Option<Self> {
-        if self.index == 0 {
-            None
-        } else {
-            Some(HeapView { index: self.index >> 1, heap: self.heap, index_map: self.index_map })
-        }
+impl<T: HeapItem> Heap<T> {
+    pub fn heapify(items: Vec<T>) -> Self {
+        Heap::heapify_with_hasher(items, RandomState::new())
     }
 
-    fn left(&self) -> Option<Self> {
-        self.checked_from_index(2 * self.index + 1)
+    /// Creates an empty heap ordered by `comparator` instead of by key: an element `a` is kept
+    /// above `b` when `comparator(a, b)` returns `Ordering::Greater`. Methods that reason about
+    /// keys directly, such as `any_ge` or `peek_key`, still assume the root has the largest key.
+    pub fn with_comparator<F>(comparator: F) -> Self
+    where
+        F: Fn(&T, &T) -> Ordering + Send + Sync + 'static,
+    {
+        Heap::heapify_with_comparator(vec![], comparator)
     }
 
-    fn right(&self) -> Option<Self> {
-        self.checked_from_index(2 * self.index + 2)
+    pub fn heapify_with_comparator<F>(items: Vec<T>, comparator: F) -> Self
+    where
+        F: Fn(&T, &T) -> Ordering + Send + Sync + 'static,
+    {
+        let index_map = HashMap::with_capacity(items.len());
+        let mut result = Heap::from_parts(items, index_map);
+        result.comparator = Some(HeapOrder::Custom(Arc::new(comparator)));
+        result.rebuild();
+        result
     }
 
-    fn checked_from_index(&self, index: usize) -> Option<Self> {
-        if index >= self.heap.len() {
-            None
-        } else {
-            Some(
-                HeapView {
-                    index,
-                    heap: self.heap,
-                    index_map: self.index_map,
-                })
+    /// Builds a heap from several runs, e.g. the sorted runs of an external sort, with a single
+    /// O(n) heapify over their concatenation. Fails if any id appears more than once.
+    pub fn merge_sorted_runs(runs: Vec<Vec<T>>) -> Result<Self, HeapError<T::Id>> {
+        let mut items = Vec::with_capacity(runs.iter().map(Vec::len).sum());
+        for run in runs {
+            items.extend(run);
         }
+        Heap::try_heapify_with_hasher(items, RandomState::new())
     }
 
-    fn value(&self) -> &T {
-        &self.heap[self.index]
+    /// Builds a heap from items already sorted by descending key, which is a valid heap as-is,
+    /// so only `index_map` is built and no sifting happens.
+    pub fn from_sorted_descending(items: Vec<T>) -> Self {
+        debug_assert!(
+            items.windows(2).all(|pair| pair[0].key() >= pair[1].key()),
+            "items must be sorted by descending key"
+        );
+        let index_map = HashMap::with_capacity(items.len());
+        let mut result = Heap::from_parts(items, index_map);
+        result.rebuild_index_map();
+        result
     }
-}
 
+    pub fn heapify_from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        let iter = iter.into_iter();
+        let mut items = Vec::with_capacity(iter.size_hint().0);
+        items.extend(iter);
+        Heap::heapify(items)
+    }
 
-struct HeapViewMut<'a, T: HeapItem> {
-    index: usize,
-    heap: &'a mut Vec<T>,
-    index_map: &'a mut HashMap<Id, usize>,
+    /// Fallible counterpart to `collect`: fails if the iterator yields the same id twice.
+    pub fn try_from_iter<I: IntoIterator<Item = T>>(iter: I) -> Result<Self, HeapError<T::Id>> {
+        let iter = iter.into_iter();
+        let mut items = Vec::with_capacity(iter.size_hint().0);
+        items.extend(iter);
+        Heap::try_heapify_with_hasher(items, RandomState::new())
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        Heap::with_capacity_and_hasher(capacity, RandomState::new())
+    }
+
+    /// Creates an empty heap that identifies elements by `id_fn` instead of `HeapItem::id`, for
+    /// payloads whose ids are assigned externally. `id_fn` must return the same id for an
+    /// element every time it is called, and all id-based methods use the ids it produces.
+    pub fn with_id_fn<F>(id_fn: F) -> Self
+    where
+        F: Fn(&T) -> T::Id + Send + Sync + 'static,
+    {
+        let mut result = Heap::heapify(vec![]);
+        result.id_fn = Some(Arc::new(id_fn));
+        result
+    }
+
+    pub fn singleton(item: T) -> Self {
+        let mut result = Heap::from_parts(vec![item], HashMap::with_capacity(1));
+        result.rebuild_index_map();
+        result
+    }
 }
 
-impl<'a, T: HeapItem> HeapViewMut<'a, T> {
-    fn parent(&self) -> Option<usize> {
-        if self.index == 0 {
-            None
-        } else {
-            Some(self.index >> 1)
-        }
+impl<T: HeapItem, S: BuildHasher> Heap<T, S> {
+    pub fn with_hasher(hash_builder: S) -> Self {
+        Heap::from_parts(vec![], HashMap::with_hasher(hash_builder))
     }
 
-    fn left_index(&self) -> Option<usize> {
-        let index = 2 * self.index + 1;
-        if index < self.heap.len() {
-            Some(index)
-        } else {
-            None
-        }
+    /// Creates an empty heap that can hold at least `capacity` elements without reallocating.
+    /// The backing array is sized to match `index_map`, whose capacity is rounded up by
+    /// `HashMap`'s load factor, so the resulting capacity is `Heap::capacity_for(capacity)`.
+    pub fn with_capacity_and_hasher(capacity: usize, hash_builder: S) -> Self {
+        let index_map = HashMap::with_capacity_and_hasher(capacity, hash_builder);
+        Heap::from_parts(Vec::with_capacity(index_map.capacity()), index_map)
     }
 
-    fn right_index(&self) -> Option<usize> {
-        let index = 2 * self.index + 2;
-        if index < self.heap.len() {
-            Some(index)
-        } else {
-            None
-        }
+    /// Returns the capacity `with_capacity(n)` settles at, which is at least `n`. The backing
+    /// array is reserved to match `index_map`, so this is whatever `HashMap` rounds `n` up to;
+    /// it is read off a scratch `HashMap` of that size rather than predicted, which costs an
+    /// allocation. Useful for sizing buffers elsewhere to agree with a heap.
+    pub fn capacity_for(n: usize) -> usize {
+        HashMap::<T::Id, usize>::with_capacity(n).capacity()
     }
 
-    fn sift_up(&mut self) {
-        while let Some(parent_index) = self.parent() {
-            if self.heap[parent_index].key() < self.heap[self.index].key() {
-                self.transpose(parent_index)
-            } else {
-                break;
-            }
-        }
+    /// The number of elements the heap can hold before either the backing array or `index_map`
+    /// must reallocate.
+    pub fn capacity(&self) -> usize {
+        self.heap.capacity().min(self.index_map.capacity())
     }
 
-    fn transpose(&mut self, dest: usize) {
-        *self.index_map.get_mut(&self.heap[self.index].id()).unwrap() = dest;
-        *self.index_map.get_mut(&self.heap[dest].id()).unwrap() = self.index;
-        self.heap.swap(self.index, dest);
-        self.index = dest;
+    /// Returns `(len(), capacity())` in one call, e.g. for metrics.
+    pub fn occupancy(&self) -> (usize, usize) {
+        (self.len(), self.capacity())
     }
 
-    fn sift_down(&mut self) {
-        let left_index = self.left_index();
-        let right_index = self.right_index();
-        match (left_index, right_index) {
-            (None, None) => {}
-            (Some(left), Some(right)) => {
-                let max = if self.heap[left].key() > self.heap[right].key() {
-                    left
-                } else {
-                    right
-                };
-                if self.heap[self.index].key() < self.heap[max].key() {
-                    self.transpose(max);
-                    self.sift_down();
-                }
-            }
-            (Some(index), None) | (None, Some(index)) => {
-                if self.heap[self.index].key() < self.heap[index].key() {
-                    self.transpose(index);
-                    self.sift_down();
-                }
+    pub fn heapify_with_hasher(items: Vec<T>, hash_builder: S) -> Self {
+        let span = op_span!("heapify", items.len());
+        let index_map = HashMap::with_capacity_and_hasher(items.len(), hash_builder);
+        let mut result = Heap::from_parts(items, index_map);
+        result.rebuild_index_map();
+        span.record_sift_steps(result.sift_all());
+        result
+    }
+
+    fn try_heapify_with_hasher(mut items: Vec<T>, hash_builder: S) -> Result<Self, HeapError<T::Id>> {
+        let mut index_map = HashMap::with_capacity_and_hasher(items.len(), hash_builder);
+        for (i, val) in items.iter_mut().enumerate() {
+            if index_map.insert(val.id(), i).is_some() {
+                return Err(HeapError::DuplicateId(val.id()));
             }
+            val.on_move(i);
         }
+        let mut result = Heap::from_parts(items, index_map);
+        result.sift_all();
+        Ok(result)
     }
-}
-
-pub struct HeapItemRefMut<'a, T: HeapItem> {
-    view: HeapViewMut<'a, T>,
-    original_key: Key,
-    original_id: Id,
-}
 
-impl<'a, T: HeapItem> Drop for HeapItemRefMut<'a, T> {
-    fn drop(&mut self) {
-        println!("restoring invariants when reference dropped");
-        let new_id = self.view.heap[self.view.index].id();
-        let new_key = self.view.heap[self.view.index].key();
-        let (_, old_index) = self.view.index_map.remove_entry(&self.original_id).unwrap();
-        debug_assert_eq!(old_index, self.view.index);
-        self.view.index_map.insert(new_id, old_index);
-        if self.original_key > new_key {
-            self.view.sift_down();
-        } else if self.original_key < new_key {
-            self.view.sift_up();
+    /// Reassembles a heap from parts previously obtained from `into_parts`. No checking is
+    /// done: `heap` must be heap-ordered, and `index_map` must hold exactly one entry per
+    /// element, mapping its id to its position in `heap`. Use `try_from_parts` if the parts
+    /// can't be trusted.
+    pub fn from_parts(heap: Vec<T>, index_map: HashMap<T::Id, usize, S>) -> Self {
+        Heap {
+            peak_len: heap.len(),
+            heap,
+            index_map,
+            max_len: None,
+            auto_shrink: false,
+            capacity_policy: CapacityPolicy::default(),
+            comparator: None,
+            id_fn: None,
+            pop_observer: None,
+            #[cfg(feature = "stats")]
+            stats: StatCounters::default(),
         }
     }
-}
 
-impl<'a, T: HeapItem> Deref for HeapItemRefMut<'a, T> {
-    type Target = T;
+    /// Switches the heap to ordering by `comparator`, with the same convention as
+    /// `with_comparator`, and re-heapifies the existing elements under it in O(n).
+    pub fn replace_comparator<F>(&mut self, comparator: F)
+    where
+        F: Fn(&T, &T) -> Ordering + Send + Sync + 'static,
+    {
+        self.comparator = Some(HeapOrder::Custom(Arc::new(comparator)));
+        self.sift_all();
+    }
 
-    fn deref(&self) -> &Self::Target {
-        self.view.heap.get(self.view.index).unwrap()
+    /// Makes elements with equal keys come out in ascending id order by re-heapifying under a
+    /// comparator on `(key, id)`. This replaces any custom comparator already in place.
+    pub fn stabilize_by_id(&mut self)
+    where
+        T::Id: Ord,
+    {
+        self.comparator = Some(HeapOrder::KeyThenId(compare_key_then_id::<T>));
+        self.sift_all();
     }
-}
 
-impl<'a, T: HeapItem> DerefMut for HeapItemRefMut<'a, T> {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        self.view.heap.get_mut(self.view.index).unwrap()
+    pub fn try_from_parts(heap: Vec<T>, index_map: HashMap<T::Id, usize, S>) -> Result<Self, HeapError<T::Id>> {
+        if !index_map_matches(&heap, &index_map, &None) {
+            Err(HeapError::InconsistentIndexMap)
+        } else if !is_heap(&heap) {
+            Err(HeapError::NotHeapOrdered)
+        } else {
+            Ok(Heap::from_parts(heap, index_map))
+        }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use std::fmt::Formatter;
-    use super::*;
+    pub fn into_parts(self) -> (Vec<T>, HashMap<T::Id, usize, S>) {
+        (self.heap, self.index_map)
+    }
 
-    fn check_invariants<T: HeapItem>(heap: &Heap<T>) {
-        for i in 0..heap.len() {
-            let view = heap.get_view_at(i);
-            if let Some(left) = view.left() {
-                assert!(left.value().key() <= view.value().key());
-            }
-            if let Some(right) = view.right() {
-                assert!(right.value().key() <= view.value().key());
-            }
-        }
-        let view = heap.get_view();
-        for (id, index) in view.index_map.iter() {
-            assert_eq!(view.heap[*index].id(), *id);
+    /// Drains all of `heaps` into a single `Vec` in descending key order, leaving each input
+    /// empty but still usable.
+    pub fn merge_in_sorted_order(heaps: &mut [Self]) -> Vec<T> {
+        let mut result = Vec::with_capacity(heaps.iter().map(Heap::len).sum());
+        let mut cursors = Heap::heapify(
+            heaps
+                .iter()
+                .enumerate()
+                .filter_map(|(source, heap)| heap.peek_key().map(|key| MergeCursor { key, source }))
+                .collect(),
+        );
+        while let Some(&MergeCursor { source, .. }) = cursors.peek() {
+            let heap = &mut heaps[source];
+            result.push(heap.pop().unwrap());
+            match heap.peek_key() {
+                Some(key) => cursors.replace_root(MergeCursor { key, source }),
+                None => cursors.pop(),
+            };
         }
+        result
     }
 
-    #[test]
-    fn test_heapify() {
-        let heap = Heap::heapify(vec![9, 8, 7, 6, 5, 4, 3, 2, 1]);
-        check_invariants(&heap);
+    /// Consumes the heap and yields its elements in O(n) total.
+    ///
+    /// **The order is unspecified** — it is whatever the backing array happens to hold, not
+    /// priority order. Pop repeatedly if you need sorted output.
+    pub fn into_iter_unordered(self) -> impl Iterator<Item = T> {
+        self.heap.into_iter()
     }
 
-    #[test]
-    fn test_value() {
-        let heap = Heap::heapify(vec![9, 8, 7, 6, 5, 4, 3, 2, 1]);
-        check_invariants(&heap);
-        let view = heap.get_view();
-        assert_eq!(view.value(), &9);
+    pub fn len(&self) -> usize {
+        self.heap.len()
     }
 
-    #[test]
-    fn left_works() {
-        let heap = Heap::heapify(vec![9, 8, 7, 6, 5, 4, 3, 2, 1]);
-        let view = heap.get_view();
-        assert_eq!(view.left().unwrap().value(), &8);
-        assert_eq!(view.left().unwrap().left().unwrap().value(), &6);
-        assert_eq!(view.left().unwrap().left().unwrap().left().unwrap().value(), &2);
-        assert_eq!(view.left().unwrap().left().unwrap().left().unwrap().left(), None);
+    pub fn is_empty(&self) -> bool {
+        self.heap.is_empty()
     }
 
-    #[test]
-    fn right_works() {
-        let heap = Heap::heapify(vec![9, 8, 7, 6, 5, 4, 3, 2, 1]);
-        let view = heap.get_view();
-        assert_eq!(view.right().unwrap().value(), &7);
-        assert_eq!(view.right().unwrap().right().unwrap().value(), &3);
-        assert_eq!(view.right().unwrap().right().unwrap().right(), None);
+    pub fn peek(&self) -> Option<&T> {
+        self.heap.first()
     }
 
-    #[test]
-    fn left_and_right_navigation() {
-        let heap = Heap::heapify(vec![9, 8, 7, 6, 5, 4, 3, 2, 1]);
-        let view = heap.get_view();
-        assert_eq!(view.left().unwrap().left().unwrap().right().unwrap().value(), &1);
+    /// Alias for `peek`.
+    pub fn front(&self) -> Option<&T> {
+        self.peek()
     }
 
-    #[test]
-    fn sift_up() {
-        let mut heap = Heap::heapify(vec![1, 2, 3, 4, 5, 6, 7, 8, 9]);
-        let mut view = heap.get_mut_view();
-        view.sift_up();
-        check_invariants(&heap);
+    /// Yields the elements on level `depth` of the tree, where the root is at depth 0. These
+    /// occupy indices `2^depth - 1 .. 2^(depth + 1) - 1` of the level-order backing array.
+    pub fn peek_at_depth(&self, depth: usize) -> impl Iterator<Item = &T> {
+        let level_start = |depth: usize| {
+            u32::try_from(depth)
+                .ok()
+                .and_then(|depth| 1usize.checked_shl(depth))
+                .map_or(usize::MAX, |width| width - 1)
+        };
+        let len = self.heap.len();
+        self.heap[level_start(depth).min(len)..level_start(depth.saturating_add(1)).min(len)].iter()
     }
 
-    #[test]
+    /// Yields every element paired with its index in the level-order backing array, where the
+    /// children of index `i` sit at `2i + 1` and `2i + 2`.
+    pub fn iter_with_index(&self) -> impl Iterator<Item = (usize, &T)> {
+        self.heap.iter().enumerate()
+    }
+
+    /// Returns the key stored at position `index` of the level-order backing array. This is a
+    /// low-level aid for inspecting the tree's structure, not a lookup by id; see `get` for that.
+    pub fn key_at_index(&self, index: usize) -> Option<Key> {
+        self.heap.get(index).map(|item| item.key())
+    }
+
+    /// Yields the elements with no children, which occupy indices `len / 2 .. len` of the
+    /// backing array. The lowest-priority element is always among them.
+    pub fn iter_leaves(&self) -> impl Iterator<Item = &T> {
+        self.heap[self.heap.len() >> 1..].iter()
+    }
+
+    /// Picks an element uniformly at random, ignoring priority.
+    #[cfg(feature = "rand")]
+    pub fn sample_uniform<R: Rng + ?Sized>(&self, rng: &mut R) -> Option<&T> {
+        if self.heap.is_empty() {
+            None
+        } else {
+            Some(&self.heap[rng.gen_range(0..self.heap.len())])
+        }
+    }
+
+    /// Picks an element with probability proportional to its key, in a single pass over the
+    /// backing array. Elements with negative keys are given weight zero and never chosen, as
+    /// are zero keys; returns `None` if no element has a positive key.
+    #[cfg(feature = "rand")]
+    pub fn weighted_sample<R: Rng + ?Sized>(&self, rng: &mut R) -> Option<&T> {
+        let mut total_weight = 0u128;
+        let mut chosen = None;
+        for item in &self.heap {
+            let weight = item.key().max(0) as u128;
+            if weight == 0 {
+                continue;
+            }
+            total_weight += weight;
+            if rng.gen_range(0..total_weight) < weight {
+                chosen = Some(item);
+            }
+        }
+        chosen
+    }
+
+    pub fn peek_id(&self) -> Option<T::Id> {
+        self.heap.first().map(|t| id_with(&self.id_fn, t))
+    }
+
+    /// Returns whether the root's id differs from `last`, the id seen at the previous poll (or
+    /// `None` if the heap was empty then). Only the identity of the root is compared, so a
+    /// change to the root's key while it stays on top is not reported.
+    pub fn root_id_changed_since(&self, last: Option<T::Id>) -> bool {
+        self.peek_id() != last
+    }
+
+    pub fn peek_key(&self) -> Option<Key> {
+        self.heap.first().map(|t| t.key())
+    }
+
+    pub fn id_set(&self) -> HashSet<T::Id> {
+        self.index_map.keys().cloned().collect()
+    }
+
+    /// Returns the smallest and largest keys. The largest is read from the root in O(1); the
+    /// smallest requires scanning the leaves. Under a custom comparator neither end is known to
+    /// hold the extremes, so every key is scanned.
+    pub fn key_range(&self) -> Option<(Key, Key)> {
+        if !self.orders_by_key() {
+            let min_key = self.heap.iter().map(|t| t.key()).min()?;
+            let max_key = self.heap.iter().map(|t| t.key()).max()?;
+            return Some((min_key, max_key));
+        }
+        let max_key = self.peek_key()?;
+        let min_key = self.iter_leaves().map(|t| t.key()).min()?;
+        Some((min_key, max_key))
+    }
+
+    /// Returns whether any element has a key of at least `key`. This is O(1) because the
+    /// maximum key always sits at the root.
+    pub fn any_ge(&self, key: Key) -> bool {
+        self.peek().is_some_and(|t| t.key() >= key)
+    }
+
+    /// Returns the median key, taking the upper of the two middle keys when the length is even.
+    /// The keys are copied into a scratch `Vec` and quickselected, so this allocates O(n) and
+    /// runs in O(n) on average.
+    pub fn median_key(&self) -> Option<Key> {
+        self.nth_smallest_key(self.heap.len() >> 1)
+    }
+
+    /// Returns the key at percentile `p` of the key distribution, so `0.0` gives the smallest
+    /// key and `1.0` the largest, rounding to the nearest rank in between. `p` is clamped to
+    /// `[0, 1]`, with NaN treated as `0`. Like `median_key`, this quickselects a scratch copy
+    /// of the keys.
+    pub fn key_percentile(&self, p: f64) -> Option<Key> {
+        let last_rank = self.heap.len().checked_sub(1)?;
+        let rank = (p.clamp(0.0, 1.0) * last_rank as f64).round() as usize;
+        self.nth_smallest_key(rank)
+    }
+
+    fn nth_smallest_key(&self, rank: usize) -> Option<Key> {
+        if rank >= self.heap.len() {
+            return None;
+        }
+        let mut keys: Vec<Key> = self.heap.iter().map(|item| item.key()).collect();
+        Some(*keys.select_nth_unstable(rank).1)
+    }
+
+    /// Sums all keys in an `i128`, which can't overflow for any heap that fits in memory.
+    pub fn total_key_sum(&self) -> i128 {
+        self.heap.iter().map(|item| i128::from(item.key())).sum()
+    }
+
+    /// Counts keys in buckets of width `bucket`, keyed by each bucket's lower bound. A bucket
+    /// whose lower bound would fall below `Key::MIN` is keyed by `Key::MIN` instead. Panics if
+    /// `bucket` isn't positive.
+    pub fn key_histogram(&self, bucket: Key) -> BTreeMap<Key, usize> {
+        assert!(bucket > 0, "bucket width must be positive");
+        let mut histogram = BTreeMap::new();
+        for item in &self.heap {
+            let key = item.key();
+            *histogram.entry(key.saturating_sub(key.rem_euclid(bucket))).or_insert(0) += 1;
+        }
+        histogram
+    }
+
+    /// Returns whether at least `n` elements have a key of at least `key`. Subtrees whose root
+    /// falls below `key` are skipped (under a custom comparator every element is checked), and
+    /// the walk stops as soon as `n` matches are found.
+    pub fn at_least_n_ge(&self, key: Key, n: usize) -> bool {
+        let mut found = 0;
+        if n == 0 {
+            return true;
+        }
+        self.visit_at_least(key, |_| {
+            found += 1;
+            found < n
+        });
+        found >= n
+    }
+
+    /// Collects the ids of every element with a key of at least `key`, in no particular order.
+    /// Subtrees whose root falls below `key` are never visited, except under a custom
+    /// comparator, where every element is checked.
+    pub fn ids_at_least(&self, key: Key) -> Vec<T::Id> {
+        let mut ids = vec![];
+        self.visit_at_least(key, |index| {
+            ids.push(id_with(&self.id_fn, &self.heap[index]));
+            true
+        });
+        ids
+    }
+
+    /// Returns the keys of the next `n` elements to be popped, in order, without touching the
+    /// heap or cloning any elements. Only the frontier of the tree below the keys taken so far
+    /// is tracked, in a scratch heap of keys, so this costs O(n log n) regardless of `len()`.
+    /// Relies on the default key ordering.
+    pub fn next_n_keys(&self, n: usize) -> Vec<Key> {
+        let mut result = Vec::with_capacity(n.min(self.heap.len()));
+        let mut frontier = Heap::with_capacity(2 * result.capacity() + 1);
+        if let Some(key) = self.peek_key() {
+            frontier.push(MergeCursor { key, source: 0 });
+        }
+        while result.len() < n {
+            let MergeCursor { key, source } = match frontier.pop() {
+                Some(cursor) => cursor,
+                None => break,
+            };
+            result.push(key);
+            for child in [2 * source + 1, 2 * source + 2] {
+                if let Some(key) = self.key_at_index(child) {
+                    frontier.push(MergeCursor { key, source: child });
+                }
+            }
+        }
+        result
+    }
+
+    /// Counts the elements with a key strictly greater than `key`, i.e. how many would be popped
+    /// before an element with that key. Subtrees rooted at or below `key` are skipped (under a
+    /// custom comparator every element is checked).
+    pub fn rank_of_key(&self, key: Key) -> usize {
+        let mut rank = 0;
+        if let Some(threshold) = key.checked_add(1) {
+            self.visit_at_least(threshold, |_| {
+                rank += 1;
+                true
+            });
+        }
+        rank
+    }
+
+    /// Whether a parent's key is always at least its children's, as under the default order and
+    /// `stabilize_by_id`, so that key-based pruning is sound.
+    fn orders_by_key(&self) -> bool {
+        !matches!(self.comparator, Some(HeapOrder::Custom(_)))
+    }
+
+    /// Calls `f` with the index of every element whose key is at least `key`, pruning subtrees
+    /// rooted below it, until `f` returns `false`. Pruning relies on key order, so under a
+    /// custom comparator this falls back to a linear scan.
+    fn visit_at_least<F: FnMut(usize) -> bool>(&self, key: Key, mut f: F) {
+        if !self.orders_by_key() {
+            for (index, item) in self.heap.iter().enumerate() {
+                #[cfg(feature = "stats")]
+                self.stats.count_comparison();
+                if item.key() >= key && !f(index) {
+                    return;
+                }
+            }
+            return;
+        }
+        let mut pending = vec![0];
+        while let Some(index) = pending.pop() {
+            if index >= self.heap.len() {
+                continue;
+            }
+            #[cfg(feature = "stats")]
+            self.stats.count_comparison();
+            if self.heap[index].key() >= key {
+                if !f(index) {
+                    return;
+                }
+                pending.push(2 * index + 2);
+                pending.push(2 * index + 1);
+            }
+        }
+    }
+
+    pub fn push(&mut self, value: T) {
+        let span = op_span!("push", self.heap.len());
+        self.append_unsifted(value);
+        let mut view = self.get_mut_view_at(self.heap.len() - 1);
+        span.record_sift_steps(view.sift_up());
+        let landed = view.index;
+        self.debug_assert_no_orphans(&[landed, self.heap.len() - 1]);
+    }
+
+    /// In debug builds, panics unless `index_map` has one entry per element and each of the
+    /// `touched` slots is mapped back to by its element's id. Called after the mutating
+    /// operations most prone to desynchronizing the two so that corruption surfaces where it
+    /// happens; it costs O(touched), leaving the full O(n) scan to `index_map_consistent`.
+    fn debug_assert_no_orphans(&self, touched: &[usize]) {
+        debug_assert!(
+            slots_match(&self.heap, &self.index_map, &self.id_fn, touched),
+            "index_map is out of sync with the backing array"
+        );
+    }
+
+    /// Checks that `index_map` holds exactly one entry per element and that each entry points
+    /// at the element with that id. Heap order is not checked. Useful after working on the
+    /// parts from `into_parts` directly.
+    pub fn index_map_consistent(&self) -> bool {
+        index_map_matches(&self.heap, &self.index_map, &self.id_fn)
+    }
+
+    /// Pushes an element that was just popped, e.g. to undo a speculative pop. When `item` still
+    /// outranks the root, as it does right after being popped, a single comparison settles that
+    /// it belongs at the top and its path from the tail is shifted down without further
+    /// comparisons. Otherwise this is an ordinary `push`.
+    pub fn reinsert_popped(&mut self, item: T) {
+        let belongs_at_root =
+            self.peek().is_some_and(|root| compare_with(&self.comparator, &self.id_fn, &item, root) != Ordering::Less);
+        if !belongs_at_root {
+            self.push(item);
+            return;
+        }
+        #[cfg(feature = "stats")]
+        self.stats.count_comparison();
+        self.append_unsifted(item);
+        let mut view = self.get_mut_view_at(self.heap.len() - 1);
+        while view.index > 0 {
+            let parent = (view.index - 1) >> 1;
+            view.transpose(parent);
+        }
+        self.debug_assert_no_orphans(&[0, self.heap.len() - 1]);
+    }
+
+    /// The most elements the heap has held at once since it was created or `reset_peak_len`
+    /// was last called.
+    pub fn peak_len(&self) -> usize {
+        self.peak_len
+    }
+
+    pub fn reset_peak_len(&mut self) {
+        self.peak_len = self.heap.len();
+    }
+
+    /// Sets the length beyond which `saturating_push` rejects new elements. Other insertion
+    /// methods ignore this limit.
+    pub fn set_max_len(&mut self, max_len: Option<usize>) {
+        self.max_len = max_len;
+    }
+
+    /// Pushes `value` unless the heap already holds `max_len` elements, in which case the heap
+    /// is left unchanged. Returns whether the value was accepted.
+    pub fn saturating_push(&mut self, value: T) -> bool {
+        if self.max_len.is_some_and(|max_len| self.heap.len() >= max_len) {
+            false
+        } else {
+            self.push(value);
+            true
+        }
+    }
+
+    /// When enabled, `pop` releases spare memory whenever fewer than a quarter of the heap's
+    /// capacity is in use. Off by default.
+    pub fn set_auto_shrink(&mut self, auto_shrink: bool) {
+        self.auto_shrink = auto_shrink;
+    }
+
+    pub fn shrink_to_fit(&mut self) {
+        self.heap.shrink_to_fit();
+        self.index_map.shrink_to_fit();
+    }
+
+    /// Chooses how the backing array grows when `push` or `reserve` needs more room. `index_map`
+    /// always grows the way `HashMap` does.
+    pub fn set_len_capacity_policy(&mut self, policy: CapacityPolicy) {
+        self.capacity_policy = policy;
+    }
+
+    pub fn reserve(&mut self, additional: usize) {
+        self.grow_for(additional);
+        self.index_map.reserve(additional);
+    }
+
+    fn grow_for(&mut self, additional: usize) {
+        match self.policy_capacity(additional) {
+            Some(target) => self.heap.reserve_exact(target - self.heap.len()),
+            None => self.heap.reserve(additional),
+        }
+    }
+
+    /// The backing array capacity `capacity_policy` calls for to fit `additional` more elements,
+    /// or `None` when they already fit or the policy leaves growth to `Vec` itself.
+    fn policy_capacity(&self, additional: usize) -> Option<usize> {
+        let capacity = self.heap.capacity();
+        let required = self.heap.len().saturating_add(additional);
+        if required <= capacity {
+            return None;
+        }
+        match self.capacity_policy {
+            CapacityPolicy::Doubling => None,
+            CapacityPolicy::Fixed(step) => {
+                let step = step.max(1);
+                let steps = (required - capacity).div_ceil(step);
+                Some(capacity.saturating_add(steps.saturating_mul(step)))
+            }
+            CapacityPolicy::GoldenRatio => {
+                Some(required.max(capacity.saturating_add(capacity.saturating_mul(5) / 8).max(4)))
+            }
+        }
+    }
+
+    /// Fallible counterpart to `reserve`, growing the backing array by the same
+    /// `capacity_policy`. If either the backing array or `index_map` fails to grow, the error is
+    /// returned, though the other may already have grown.
+    pub fn try_reserve(&mut self, additional: usize) -> Result<(), TryReserveError> {
+        match self.policy_capacity(additional) {
+            Some(target) => self.heap.try_reserve_exact(target - self.heap.len())?,
+            None => self.heap.try_reserve(additional)?,
+        }
+        self.index_map.try_reserve(additional)
+    }
+
+    fn append_unsifted(&mut self, mut value: T) {
+        self.grow_for(1);
+        let index = self.heap.len();
+        self.index_map.insert(id_with(&self.id_fn, &value), index);
+        value.on_move(index);
+        self.heap.push(value);
+        self.peak_len = self.peak_len.max(self.heap.len());
+    }
+
+    /// Checked counterpart to `extend`, with the same batching: a batch larger than the heap is
+    /// appended wholesale and re-heapified in O(n), and a smaller one is pushed element by
+    /// element. Fails without modifying the heap if any id is repeated within `items` or
+    /// already present.
+    pub fn try_extend(&mut self, items: Vec<T>) -> Result<(), HeapError<T::Id>> {
+        self.check_new_ids(&items)?;
+        self.append_batch(items);
+        Ok(())
+    }
+
+    /// Clones `items` into the heap, with the same batching as `extend`. Fails without
+    /// modifying the heap if any id is repeated within `items` or already present.
+    pub fn grow_from_slice(&mut self, items: &[T]) -> Result<(), HeapError<T::Id>> {
+        self.check_new_ids(items)?;
+        self.append_batch(items.to_vec());
+        Ok(())
+    }
+
+    fn check_new_ids(&self, items: &[T]) -> Result<(), HeapError<T::Id>> {
+        let mut batch_ids = HashSet::with_capacity(items.len());
+        for item in items {
+            let id = id_with(&self.id_fn, item);
+            if self.index_map.contains_key(&id) || !batch_ids.insert(id) {
+                return Err(HeapError::DuplicateId(id_with(&self.id_fn, item)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Inserts a batch of elements, appending them all and re-heapifying when the batch is
+    /// larger than the heap (O(n) overall) and pushing them one at a time otherwise.
+    fn append_batch(&mut self, items: Vec<T>) {
+        self.reserve(items.len());
+        if items.len() > self.heap.len() {
+            for item in items {
+                self.append_unsifted(item);
+            }
+            self.sift_all();
+        } else {
+            for item in items {
+                self.push(item);
+            }
+        }
+    }
+
+    /// Registers `observer` to be called with every element popped from the root, whether by
+    /// `pop` or by anything built on it such as `drain_while` or `drain_sorted`, replacing any
+    /// previous observer. Only elements a `DrainSorted` actually yields are observed; those it
+    /// discards when dropped early are not. Observers are not carried over by `clone`.
+    pub fn set_pop_observer<F: FnMut(&T) + Send + Sync + 'static>(&mut self, observer: F) {
+        self.pop_observer = Some(Box::new(observer));
+    }
+
+    pub fn clear_pop_observer(&mut self) {
+        self.pop_observer = None;
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        self.pop_with_id().map(|(_, item)| item)
+    }
+
+    /// Alias for `pop`.
+    pub fn pop_front(&mut self) -> Option<T> {
+        self.pop()
+    }
+
+    /// Pops the root only if it is still the element with the given id.
+    pub fn pop_if_root_id(&mut self, id: T::Id) -> Option<T> {
+        if self.peek_id() == Some(id) {
+            self.pop()
+        } else {
+            None
+        }
+    }
+
+    /// Pops the root only if its key is still `expected_key`. Unlike `pop_if_root_id`, any
+    /// element with that key qualifies.
+    pub fn compare_and_pop(&mut self, expected_key: Key) -> Option<T> {
+        if self.peek_key() == Some(expected_key) {
+            self.pop()
+        } else {
+            None
+        }
+    }
+
+    pub fn pop_with_id(&mut self) -> Option<(T::Id, T)> {
+        if self.heap.is_empty() {
+            None
+        } else {
+            let span = op_span!("pop", self.heap.len());
+            let last_index = self.heap.len() - 1;
+            self.get_mut_view().transpose(last_index);
+            let id = id_with(&self.id_fn, &self.heap[last_index]);
+            self.index_map.remove(&id);
+            let result = self.heap.pop().unwrap();
+            let mut view = self.get_mut_view();
+            span.record_sift_steps(view.sift_down());
+            let landed = view.index;
+            if self.auto_shrink && self.heap.len() * 4 < self.capacity() {
+                self.shrink_to_fit();
+            }
+            self.debug_assert_no_orphans(&[0, landed]);
+            if let Some(observer) = self.pop_observer.as_mut() {
+                observer(&result);
+            }
+            Some((id, result))
+        }
+    }
+
+    /// Replaces the root with `value` and returns the old root, restoring heap order around the
+    /// new value. On an empty heap `value` is simply pushed.
+    pub fn replace_root(&mut self, value: T) -> Option<T> {
+        let old_root = self.write_root(value)?;
+        self.get_mut_view().restore();
+        Some(old_root)
+    }
+
+    /// Like `replace_root`, but only sifts the new value down, skipping the sift-up check
+    /// entirely. This is the cheaper choice when `new_root` is known to belong at or near the
+    /// top of the heap; prefer `replace_root` when that isn't known.
+    pub fn swap_remove_root_to(&mut self, new_root: T) -> Option<T> {
+        let old_root = self.write_root(new_root)?;
+        self.get_mut_view().sift_down();
+        Some(old_root)
+    }
+
+    fn write_root(&mut self, value: T) -> Option<T> {
+        if self.heap.is_empty() {
+            self.push(value);
+            return None;
+        }
+        let old_root = mem::replace(&mut self.heap[0], value);
+        self.index_map.remove(&id_with(&self.id_fn, &old_root));
+        self.index_map.insert(id_with(&self.id_fn, &self.heap[0]), 0);
+        self.heap[0].on_move(0);
+        Some(old_root)
+    }
+
+    pub fn remove(&mut self, id: T::Id) -> Option<T> {
+        let index = *self.index_map.get(&id)?;
+        Some(self.remove_at(index))
+    }
+
+    fn remove_at(&mut self, index: usize) -> T {
+        let last_index = self.heap.len() - 1;
+        self.get_mut_view_at(index).transpose(last_index);
+        let result = self.heap.pop().unwrap();
+        self.index_map.remove(&id_with(&self.id_fn, &result));
+        let mut landed = index;
+        if index < self.heap.len() {
+            let mut view = self.get_mut_view_at(index);
+            view.restore();
+            landed = view.index;
+        }
+        self.debug_assert_no_orphans(&[index, landed]);
+        result
+    }
+
+    /// Returns the id of the lowest-priority element. The minimum of a max-heap is always a
+    /// leaf, so only the leaves are scanned, but this is still O(n).
+    pub fn find_min_id(&self) -> Option<T::Id> {
+        self.min_leaf_index().map(|index| id_with(&self.id_fn, &self.heap[index]))
+    }
+
+    /// Removes and returns the lowest-priority element: an O(n) leaf scan followed by an
+    /// O(log n) removal.
+    pub fn pop_min(&mut self) -> Option<T> {
+        let index = self.min_leaf_index()?;
+        Some(self.remove_at(index))
+    }
+
+    /// Removes the lowest-priority element only if it passes `pred`, e.g. to evict it only
+    /// when it falls below some floor. Otherwise the heap is left untouched.
+    pub fn try_pop_min_if<F: FnOnce(&T) -> bool>(&mut self, pred: F) -> Option<T> {
+        let index = self.min_leaf_index()?;
+        if pred(&self.heap[index]) {
+            Some(self.remove_at(index))
+        } else {
+            None
+        }
+    }
+
+    fn min_leaf_index(&self) -> Option<usize> {
+        (self.heap.len() >> 1..self.heap.len())
+            .min_by(|&a, &b| compare_with(&self.comparator, &self.id_fn, &self.heap[a], &self.heap[b]))
+    }
+
+    /// Pops elements off the top of the heap for as long as `f` holds for the current root.
+    /// Since elements come out in descending key order, this is only meaningful when `f` is
+    /// monotone in the key (e.g. `|t| t.key() >= threshold`).
+    pub fn drain_while<F: FnMut(&T) -> bool>(&mut self, mut f: F) -> Vec<T> {
+        let mut result = vec![];
+        while self.peek().is_some_and(&mut f) {
+            result.push(self.pop().unwrap());
+        }
+        result
+    }
+
+    /// Pops every element whose key equals the root's, i.e. everything tied for the top.
+    pub fn pop_ties(&mut self) -> Vec<T> {
+        match self.peek_key() {
+            Some(top) => self.drain_while(|t| t.key() == top),
+            None => vec![],
+        }
+    }
+
+    /// Pops from the root until only `target` elements remain, returning the popped elements in
+    /// priority order. Returns nothing if the heap already has `target` elements or fewer.
+    pub fn pop_until_len(&mut self, target: usize) -> Vec<T> {
+        let count = self.heap.len().saturating_sub(target);
+        std::iter::from_fn(|| self.pop()).take(count).collect()
+    }
+
+    /// Pops up to `n` elements from the root, returning them in priority order.
+    pub fn pop_n(&mut self, n: usize) -> Vec<T> {
+        std::iter::from_fn(|| self.pop()).take(n).collect()
+    }
+
+    /// Adds `items` with the same batching as `extend`, then pops the top `n` of the combined
+    /// heap in priority order.
+    pub fn batch_push_then_pop_n(&mut self, items: Vec<T>, n: usize) -> Vec<T> {
+        self.append_batch(items);
+        self.pop_n(n)
+    }
+
+    /// Removes every element whose id is in `ids` and re-heapifies the survivors once. Ids that
+    /// aren't present are ignored.
+    pub fn bulk_remove(&mut self, ids: &[T::Id]) -> Vec<T> {
+        let mut marked = vec![false; self.heap.len()];
+        for id in ids {
+            if let Some(&index) = self.index_map.get(id) {
+                marked[index] = true;
+            }
+        }
+        self.extract_marked(marked)
+    }
+
+    /// Removes the element with the given id together with every element below it in the tree,
+    /// then re-heapifies the rest. "Subtree" here is purely structural: it reflects where
+    /// elements happen to sit in the backing array, not any relationship between them. The
+    /// removed elements are returned in level order, starting with the named one.
+    pub fn remove_subtree(&mut self, id: T::Id) -> Vec<T> {
+        let root = match self.index_map.get(&id) {
+            Some(&index) => index,
+            None => return vec![],
+        };
+        let mut marked = vec![false; self.heap.len()];
+        let mut pending = vec![root];
+        while let Some(index) = pending.pop() {
+            if index < marked.len() {
+                marked[index] = true;
+                pending.push(2 * index + 1);
+                pending.push(2 * index + 2);
+            }
+        }
+        self.extract_marked(marked)
+    }
+
+    /// Keeps only the elements whose id passes `f`, then re-heapifies the survivors once. Ids
+    /// are read from `index_map`, so elements are never inspected.
+    pub fn retain_ids<F: FnMut(&T::Id) -> bool>(&mut self, mut f: F) {
+        let mut marked = vec![false; self.heap.len()];
+        for (id, &index) in self.index_map.iter() {
+            marked[index] = !f(id);
+        }
+        self.extract_marked(marked);
+    }
+
+    /// Keeps only the elements for which `f` returns `true`, then re-heapifies the survivors
+    /// once.
+    pub fn retain<F: FnMut(&T) -> bool>(&mut self, f: F) {
+        self.retain_and_collect(f);
+    }
+
+    /// Like `retain`, but hands back the elements that failed `f` instead of dropping them.
+    /// They are returned in backing-array order, not priority order.
+    pub fn retain_and_collect<F: FnMut(&T) -> bool>(&mut self, mut f: F) -> Vec<T> {
+        let marked = self.heap.iter().map(|item| !f(item)).collect();
+        self.extract_marked(marked)
+    }
+
+    fn extract_marked(&mut self, marked: Vec<bool>) -> Vec<T> {
+        let mut removed = vec![];
+        let mut kept = Vec::with_capacity(self.heap.len());
+        for (item, is_marked) in self.heap.drain(..).zip(marked) {
+            if is_marked {
+                removed.push(item);
+            } else {
+                kept.push(item);
+            }
+        }
+        self.heap = kept;
+        self.rebuild();
+        removed
+    }
+
+    /// Splits the heap in two: elements with a key of at least `pivot`, including those equal to
+    /// it, and the rest. The first half is `self` with the low elements taken out, so it keeps
+    /// all its settings; the second half shares only the hasher, comparator and id function.
+    pub fn split_at_key(mut self, pivot: Key) -> (Self, Self)
+    where
+        S: Clone,
+    {
+        let low_items = self.retain_and_collect(|item| item.key() >= pivot);
+        let index_map = HashMap::with_capacity_and_hasher(low_items.len(), self.index_map.hasher().clone());
+        let mut low = Heap::from_parts(low_items, index_map);
+        low.comparator = self.comparator.clone();
+        low.id_fn = self.id_fn.clone();
+        low.rebuild();
+        (self, low)
+    }
+
+    /// Removes and returns the `n` elements with the smallest keys, in no particular order. They
+    /// are found with a bounded max-heap of the best `n` candidates in O(len log n), then
+    /// removed together with a single re-heapify.
+    pub fn drain_bottom_n(&mut self, n: usize) -> Vec<T> {
+        if n == 0 {
+            return vec![];
+        }
+        let mut candidates = Heap::with_capacity(n.min(self.heap.len()));
+        for (source, item) in self.heap.iter().enumerate() {
+            let key = item.key();
+            if candidates.len() < n {
+                candidates.push(MergeCursor { key, source });
+            } else if candidates.peek_key().is_some_and(|largest| key < largest) {
+                candidates.replace_root(MergeCursor { key, source });
+            }
+        }
+        let mut marked = vec![false; self.heap.len()];
+        for candidate in candidates.into_iter_unordered() {
+            marked[candidate.source] = true;
+        }
+        self.extract_marked(marked)
+    }
+
+    /// Repairs a heap holding several elements with the same id, which can happen since `push`
+    /// doesn't check ids. One element per id is kept according to `keep`, the rest are
+    /// dropped, and `index_map` and heap order are rebuilt. Returns how many were dropped.
+    pub fn dedup_by_id(&mut self, keep: DedupKeep) -> usize {
+        let mut kept: HashMap<T::Id, usize> = HashMap::with_capacity(self.heap.len());
+        let mut marked = vec![false; self.heap.len()];
+        for (index, item) in self.heap.iter().enumerate() {
+            let id = id_with(&self.id_fn, item);
+            match kept.get_mut(&id) {
+                None => {
+                    kept.insert(id, index);
+                }
+                Some(earlier) => {
+                    if keep == DedupKeep::HighestKey && item.key() > self.heap[*earlier].key() {
+                        marked[*earlier] = true;
+                        *earlier = index;
+                    } else {
+                        marked[index] = true;
+                    }
+                }
+            }
+        }
+        self.extract_marked(marked).len()
+    }
+
+    /// Merges elements that `same` considers equivalent. Each element is compared against one
+    /// representative per group found so far and folded into the first match with `merge`, so
+    /// this is O(n * groups). Since merging can drop elements and change ids, `index_map` is
+    /// rebuilt from scratch and the heap re-heapified afterwards.
+    pub fn coalesce<F, G>(&mut self, same: F, merge: G)
+    where
+        F: Fn(&T, &T) -> bool,
+        G: Fn(T, T) -> T,
+    {
+        let mut groups: Vec<T> = Vec::with_capacity(self.heap.len());
+        for item in self.heap.drain(..) {
+            match groups.iter().position(|group| same(group, &item)) {
+                Some(index) => {
+                    let group = groups.swap_remove(index);
+                    groups.push(merge(group, item));
+                }
+                None => groups.push(item),
+            }
+        }
+        self.heap = groups;
+        self.rebuild();
+    }
+
+    /// Shrinks the heap to `new_len` elements by discarding the ones with the smallest keys.
+    /// Does nothing if the heap already holds no more than `new_len` elements.
+    pub fn truncate_to_len(&mut self, new_len: usize) {
+        if new_len < self.heap.len() {
+            let comparator = &self.comparator;
+            let id_fn = &self.id_fn;
+            self.heap.select_nth_unstable_by(new_len, |a, b| compare_with(comparator, id_fn, b, a));
+            self.heap.truncate(new_len);
+            self.rebuild();
+        }
+    }
+
+    /// Renumbers the elements with the dense ids `0..len()`, assigned in backing-array order,
+    /// and returns a map from each old id to its new one. Ids are rewritten through `SetId`;
+    /// keys and positions are untouched, so only `index_map` is rebuilt. Panics on a heap built
+    /// with `with_id_fn`, whose ids `set_id` can't rewrite.
+    pub fn compact_ids(&mut self) -> HashMap<Id, Id>
+    where
+        T: SetId + HeapItem<Id = Id>,
+    {
+        assert!(self.id_fn.is_none(), "compact_ids can't renumber ids derived by an id function");
+        let mut remap = HashMap::with_capacity(self.heap.len());
+        for (new_id, item) in (0..).zip(self.heap.iter_mut()) {
+            remap.insert(id_with(&self.id_fn, item), new_id);
+            item.set_id(new_id);
+        }
+        self.rebuild_index_map();
+        remap
+    }
+
+    /// Swaps the backing array with `v`: the heap takes over `v`'s elements, re-heapified, and
+    /// `v` receives the old elements in no particular order. Both allocations are kept, which
+    /// suits double-buffering. Fails without touching either side if `v` repeats an id.
+    pub fn swap_with_vec(&mut self, v: &mut Vec<T>) -> Result<(), HeapError<T::Id>> {
+        let mut ids = HashSet::with_capacity(v.len());
+        if let Some(item) = v.iter().find(|item| !ids.insert(id_with(&self.id_fn, item))) {
+            return Err(HeapError::DuplicateId(id_with(&self.id_fn, item)));
+        }
+        mem::swap(&mut self.heap, v);
+        self.peak_len = self.peak_len.max(self.heap.len());
+        self.rebuild();
+        Ok(())
+    }
+
+    /// Discards `index_map` and repopulates it from the backing array, without reordering
+    /// any elements.
+    pub fn rebuild_index_map(&mut self) {
+        self.index_map.clear();
+        for (i, val) in self.heap.iter_mut().enumerate() {
+            self.index_map.insert(id_with(&self.id_fn, val), i);
+            val.on_move(i);
+        }
+    }
+
+    /// Rebuilds `index_map` and then restores heap order over the whole backing array.
+    pub fn rebuild(&mut self) {
+        self.rebuild_index_map();
+        self.sift_all();
+    }
+
+    /// Checks `index_map` and heap order for corruption, rebuilds whatever is broken, and
+    /// reports what was found.
+    pub fn repair(&mut self) -> RepairReport {
+        let mut report = RepairReport::default();
+        for (id, &index) in self.index_map.iter() {
+            match self.heap.get(index) {
+                None => report.dangling_entries += 1,
+                Some(item) if id_with(&self.id_fn, item) != *id => report.misdirected_entries += 1,
+                Some(_) => {}
+            }
+        }
+        report.unmapped_elements = self
+            .heap
+            .iter()
+            .filter(|item| !self.index_map.contains_key(&id_with(&self.id_fn, item)))
+            .count();
+        report.reordered = !is_heap_by(&self.heap, &self.comparator, &self.id_fn);
+        if !report.is_clean() {
+            self.rebuild();
+        }
+        report
+    }
+
+    fn sift_all(&mut self) -> usize {
+        self.get_mut_view().sift_all()
+    }
+
+    /// Returns the comparisons and swaps performed while sifting since the heap was created or
+    /// the counters were last reset.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> Stats {
+        self.stats.snapshot()
+    }
+
+    #[cfg(feature = "stats")]
+    pub fn reset_stats(&self) {
+        self.stats.reset();
+    }
+
+    /// Returns an iterator that pops elements in priority order as it is advanced. Whatever is
+    /// left when it is dropped is discarded, so the heap always ends up empty but keeps its
+    /// allocation.
+    pub fn drain_sorted(&mut self) -> DrainSorted<'_, T, S> {
+        DrainSorted { heap: self }
+    }
+
+    /// Empties the heap onto the end of `sink` in priority order.
+    pub fn drain_sorted_into(&mut self, sink: &mut Vec<T>) {
+        sink.reserve(self.heap.len());
+        while let Some(item) = self.pop() {
+            sink.push(item);
+        }
+    }
+
+    /// Consumes the heap, returning its elements in priority order.
+    pub fn into_sorted_vec(mut self) -> Vec<T> {
+        let mut result = vec![];
+        self.drain_sorted_into(&mut result);
+        result
+    }
+
+    /// Returns a copy of the elements in priority order, leaving the heap untouched. The backing
+    /// array is cloned and sorted directly, which beats popping a cloned heap.
+    pub fn clone_into_sorted_vec(&self) -> Vec<T> {
+        let mut result = self.heap.clone();
+        let comparator = &self.comparator;
+        let id_fn = &self.id_fn;
+        result.sort_by(|a, b| compare_with(comparator, id_fn, b, a));
+        result
+    }
+
+    /// Consumes the heap, returning its elements in priority order like `into_sorted_vec`, but
+    /// by sorting the backing array in parallel instead of popping. The heap structure is
+    /// ignored entirely, so this gives up the head start heapify provides in exchange for
+    /// using every core; it pays off for large heaps. Elements of equal priority may come out
+    /// in any order.
+    #[cfg(feature = "rayon")]
+    pub fn par_into_sorted_vec(self) -> Vec<T>
+    where
+        T: Send,
+    {
+        let mut result = self.heap;
+        let comparator = &self.comparator;
+        let id_fn = &self.id_fn;
+        result.par_sort_unstable_by(|a, b| compare_with(comparator, id_fn, b, a));
+        result
+    }
+
+    /// Consumes the heap, returning its elements in ascending priority order. This is the
+    /// descending drain reversed in place, which is cheaper than building a min-heap.
+    pub fn into_sorted_vec_ascending(self) -> Vec<T> {
+        let mut result = self.into_sorted_vec();
+        result.reverse();
+        result
+    }
+
+    /// Consumes the heap and leaks its elements, in priority order, as a `'static` slice. The
+    /// memory is deliberately never freed, so this is only meant for tables built once and
+    /// read for the rest of the process's life.
+    pub fn leak_sorted(self) -> &'static [T] {
+        Box::leak(self.into_sorted_vec().into_boxed_slice())
+    }
+
+    /// Consumes the heap, returning its elements sorted by `cmp` instead of by priority. The
+    /// heap is drained in priority order first and then stably sorted, so elements that `cmp`
+    /// considers equal stay in priority order. That costs O(n log n) for the drain plus
+    /// another O(n log n) for the sort.
+    pub fn into_sorted_vec_by<F: FnMut(&T, &T) -> Ordering>(self, cmp: F) -> Vec<T> {
+        let mut result = self.into_sorted_vec();
+        result.sort_by(cmp);
+        result
+    }
+
+    /// Empties the heap, returning the ids in the order their elements were popped. Handy for
+    /// asserting an exact ordering in tests.
+    pub fn pop_ids(&mut self) -> Vec<T::Id> {
+        std::iter::from_fn(|| self.pop_with_id()).map(|(id, _)| id).collect()
+    }
+
+    /// Returns the ids of the `n` elements that would be popped next, without modifying the
+    /// heap. Works on a scratch copy, so it costs O(len) to clone plus O(n log len) to pop.
+    pub fn peek_n_ids(&self, n: usize) -> Vec<T::Id>
+    where
+        S: Clone,
+    {
+        let mut scratch = self.clone();
+        std::iter::from_fn(|| scratch.pop_with_id()).take(n).map(|(id, _)| id).collect()
+    }
+
+    /// Yields the elements in priority order as pages of `chunk` elements, the last of which may
+    /// be shorter. Pages are popped lazily from a scratch copy, so `self` is untouched.
+    ///
+    /// Panics if `chunk` is 0.
+    pub fn iter_sorted_by_key_desc_chunks(&self, chunk: usize) -> impl Iterator<Item = Vec<T>>
+    where
+        S: Clone,
+    {
+        assert!(chunk > 0, "chunk size must be positive");
+        let mut scratch = self.clone();
+        std::iter::from_fn(move || {
+            let page: Vec<T> = std::iter::from_fn(|| scratch.pop()).take(chunk).collect();
+            if page.is_empty() {
+                None
+            } else {
+                Some(page)
+            }
+        })
+    }
+
+    /// Folds over the elements in priority order without disturbing the heap. The elements are
+    /// cloned into a scratch heap which is popped to exhaustion, so this costs O(n log n).
+    pub fn fold_sorted<B, F: FnMut(B, &T) -> B>(&self, init: B, mut f: F) -> B
+    where
+        S: Clone,
+    {
+        let mut scratch = self.clone();
+        let mut acc = init;
+        while let Some(item) = scratch.pop() {
+            acc = f(acc, &item);
+        }
+        acc
+    }
+
+    #[cfg(test)]
+    fn get_view(&self) -> HeapView<'_, T, S> {
+        self.get_view_at(0)
+    }
+
+    #[cfg(test)]
+    fn get_view_at(&self, index: usize) -> HeapView<'_, T, S> {
+        HeapView {
+            index,
+            heap: &self.heap,
+            index_map: &self.index_map,
+        }
+    }
+
+    fn get_mut_view(&mut self) -> HeapViewMut<'_, T, S> {
+        self.get_mut_view_at(0)
+    }
+
+    fn get_mut_view_at(&mut self, index: usize) -> HeapViewMut<'_, T, S> {
+        HeapViewMut {
+            index,
+            heap: &mut self.heap,
+            index_map: &mut self.index_map,
+            comparator: &self.comparator,
+            id_fn: &self.id_fn,
+            #[cfg(feature = "stats")]
+            stats: &self.stats,
+        }
+    }
+
+    pub fn get_mut(&mut self, id: T::Id) -> Option<HeapItemRefMut<'_, T, S>> {
+        let index = *self.index_map.get(&id)?;
+        Some(self.guard_at(index))
+    }
+
+    /// Borrows the lowest-priority element mutably, found by the same leaf scan as
+    /// `find_min_id`. Heap order is restored when the guard is dropped, so raising its key
+    /// lets it climb as far as it needs to.
+    pub fn peek_min_mut(&mut self) -> Option<HeapItemRefMut<'_, T, S>> {
+        let index = self.min_leaf_index()?;
+        Some(self.guard_at(index))
+    }
+
+    fn guard_at(&mut self, index: usize) -> HeapItemRefMut<'_, T, S> {
+        let original_key = self.heap[index].key();
+        let original_id = id_with(&self.id_fn, &self.heap[index]);
+        HeapItemRefMut {
+            view: self.get_mut_view_at(index),
+            original_key,
+            original_id,
+            mutated: false,
+        }
+    }
+
+    /// Restores heap order around the element with the given id after its key changed in a way
+    /// the heap couldn't observe, such as through interior mutability. Returns whether the
+    /// element moved.
+    pub fn reorder(&mut self, id: T::Id) -> bool {
+        match self.index_map.get(&id) {
+            Some(&index) => self.get_mut_view_at(index).restore() > 0,
+            None => false,
+        }
+    }
+
+    /// Borrows two distinct elements mutably at once. Heap order is restored for both when the
+    /// returned guard is dropped: in O(log n) if at most one key changed, but by re-heapifying
+    /// the whole array if both did or a custom comparator is in use, since two displaced
+    /// elements can't be repaired one at a time.
+    pub fn get_pair_mut(&mut self, a: T::Id, b: T::Id) -> Option<HeapPairRefMut<'_, T, S>> {
+        if a == b {
+            return None;
+        }
+        let indices = (*self.index_map.get(&a)?, *self.index_map.get(&b)?);
+        let original_keys = (self.heap[indices.0].key(), self.heap[indices.1].key());
+        Some(HeapPairRefMut {
+            view: self.get_mut_view_at(indices.0),
+            indices,
+            original_ids: (a, b),
+            original_keys,
+        })
+    }
+
+    /// Sets the key of the element with the given id and restores heap order. Returns whether
+    /// the id was present.
+    pub fn change_key(&mut self, id: T::Id, key: Key) -> bool
+    where
+        T: SetKey,
+    {
+        match self.get_mut(id) {
+            Some(mut item) => {
+                item.set_key(key);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Recomputes every element's key as `f(&element)`, then restores heap order with a single
+    /// O(n) heapify rather than sifting after each change.
+    pub fn bulk_change_keys_by<F: Fn(&T) -> Key>(&mut self, f: F)
+    where
+        T: SetKey,
+    {
+        for item in self.heap.iter_mut() {
+            let key = f(item);
+            item.set_key(key);
+        }
+        self.sift_all();
+    }
+
+    /// Replaces every element with `f(element)` and then re-heapifies once, since keys may have
+    /// changed. `f` must keep each element's id, which is checked in debug builds, so
+    /// `index_map` is left as it is.
+    pub fn replace_all<F: Fn(T) -> T>(&mut self, f: F) {
+        let id_fn = &self.id_fn;
+        self.heap = mem::take(&mut self.heap)
+            .into_iter()
+            .map(|item| {
+                let id = id_with(id_fn, &item);
+                let replaced = f(item);
+                debug_assert!(id_with(id_fn, &replaced) == id, "replace_all must preserve ids");
+                replaced
+            })
+            .collect();
+        self.sift_all();
+    }
+
+    /// Swaps the keys of the root and the lowest-priority leaf, demoting one and promoting the
+    /// other, and restores heap order around both. Returns clones of the demoted and promoted
+    /// elements, in that order, or `None` if the heap has fewer than two elements.
+    pub fn swap_root_with_leaf_min(&mut self) -> Option<(T, T)>
+    where
+        T: SetKey,
+    {
+        if self.heap.len() < 2 {
+            return None;
+        }
+        let root_id = id_with(&self.id_fn, &self.heap[0]);
+        let leaf_id = id_with(&self.id_fn, &self.heap[self.min_leaf_index()?]);
+        {
+            let mut pair = self.get_pair_mut(root_id.clone(), leaf_id.clone())?;
+            let (root, leaf) = pair.pair_mut();
+            let root_key = root.key();
+            root.set_key(leaf.key());
+            leaf.set_key(root_key);
+        }
+        Some((self.get(root_id)?.clone(), self.get(leaf_id)?.clone()))
+    }
+
+    /// Exchanges the payloads of two elements while each slot keeps its key, so positions stay
+    /// put under key ordering. Whole items are swapped and then their keys are set back, which
+    /// means ids travel with the payloads. Returns whether both ids were present and distinct.
+    pub fn swap_payloads_keep_keys(&mut self, a: T::Id, b: T::Id) -> bool
+    where
+        T: SetKey,
+    {
+        match self.get_pair_mut(a, b) {
+            Some(mut pair) => {
+                let (first, second) = pair.pair_mut();
+                let keys = (first.key(), second.key());
+                mem::swap(first, second);
+                first.set_key(keys.0);
+                second.set_key(keys.1);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn get(&self, id: T::Id) -> Option<&T> {
+        Some(&self.heap[*self.index_map.get(&id)?])
+    }
+
+    pub fn contains(&self, id: T::Id) -> bool {
+        self.index_map.contains_key(&id)
+    }
+
+    /// Returns whether any of `ids` is present, stopping at the first hit.
+    pub fn contains_any(&self, ids: &[T::Id]) -> bool {
+        ids.iter().any(|id| self.index_map.contains_key(id))
+    }
+
+    /// Returns whether every one of `ids` is present, stopping at the first miss. Trivially
+    /// true for an empty slice.
+    pub fn contains_all(&self, ids: &[T::Id]) -> bool {
+        ids.iter().all(|id| self.index_map.contains_key(id))
+    }
+
+    /// Swaps the element with the given id for `value`, returning the old element. `value` may
+    /// carry a different id, in which case the old id is retired from the heap. Panics if that
+    /// new id already belongs to another element.
+    pub fn replace_by_id(&mut self, id: T::Id, value: T) -> Option<T> {
+        let index = *self.index_map.get(&id)?;
+        let new_id = id_with(&self.id_fn, &value);
+        assert!(
+            new_id == id || !self.index_map.contains_key(&new_id),
+            "replacement id {:?} already belongs to another element",
+            new_id
+        );
+        let mut item = self.guard_at(index);
+        Some(mem::replace(&mut *item, value))
+    }
+}
+
+
+pub struct DrainSorted<'a, T: HeapItem, S: BuildHasher = RandomState> {
+    heap: &'a mut Heap<T, S>,
+}
+
+impl<'a, T: HeapItem, S: BuildHasher> Iterator for DrainSorted<'a, T, S> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.heap.pop()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.heap.len(), Some(self.heap.len()))
+    }
+}
+
+impl<'a, T: HeapItem, S: BuildHasher> ExactSizeIterator for DrainSorted<'a, T, S> {}
+
+impl<'a, T: HeapItem, S: BuildHasher> Drop for DrainSorted<'a, T, S> {
+    fn drop(&mut self) {
+        self.heap.heap.clear();
+        self.heap.index_map.clear();
+    }
+}
+
+#[cfg(test)]
+#[derive(Debug)]
+struct HeapView<'a, T: HeapItem, S> {
+    index: usize,
+    heap: &'a Vec<T>,
+    index_map: &'a HashMap<T::Id, usize, S>,
+}
+
+#[cfg(test)]
+impl<'a, T: HeapItem + PartialEq, S: BuildHasher> PartialEq for HeapView<'a, T, S> {
+    fn eq(&self, other: &Self) -> bool {
+        self.index == other.index && self.heap == other.heap && self.index_map == other.index_map
+    }
+}
+
+#[cfg(test)]
+impl<'a, T: HeapItem, S> HeapView<'a, T, S> {
+    fn parent(&self) -> Option<Self> {
+        if self.index == 0 {
+            None
+        } else {
+            Some(HeapView { index: (self.index - 1) >> 1, heap: self.heap, index_map: self.index_map })
+        }
+    }
+
+    fn left(&self) -> Option<Self> {
+        self.checked_from_index(2 * self.index + 1)
+    }
+
+    fn right(&self) -> Option<Self> {
+        self.checked_from_index(2 * self.index + 2)
+    }
+
+    fn checked_from_index(&self, index: usize) -> Option<Self> {
+        if index >= self.heap.len() {
+            None
+        } else {
+            Some(
+                HeapView {
+                    index,
+                    heap: self.heap,
+                    index_map: self.index_map,
+                })
+        }
+    }
+
+    fn value(&self) -> &T {
+        &self.heap[self.index]
+    }
+}
+
+
+struct HeapViewMut<'a, T: HeapItem, S> {
+    index: usize,
+    heap: &'a mut Vec<T>,
+    index_map: &'a mut HashMap<T::Id, usize, S>,
+    comparator: &'a Option<HeapOrder<T>>,
+    id_fn: &'a Option<IdFn<T>>,
+    #[cfg(feature = "stats")]
+    stats: &'a StatCounters,
+}
+
+impl<'a, T: HeapItem, S: BuildHasher> HeapViewMut<'a, T, S> {
+    fn debug_assert_no_orphans(&self, touched: &[usize]) {
+        debug_assert!(
+            slots_match(self.heap, self.index_map, self.id_fn, touched),
+            "index_map is out of sync with the backing array"
+        );
+    }
+
+    fn parent(&self) -> Option<usize> {
+        if self.index == 0 {
+            None
+        } else {
+            Some((self.index - 1) >> 1)
+        }
+    }
+
+    fn left_index(&self) -> Option<usize> {
+        let index = 2 * self.index + 1;
+        if index < self.heap.len() {
+            Some(index)
+        } else {
+            None
+        }
+    }
+
+    fn right_index(&self) -> Option<usize> {
+        let index = 2 * self.index + 2;
+        if index < self.heap.len() {
+            Some(index)
+        } else {
+            None
+        }
+    }
+
+    fn outranks(&self, a: usize, b: usize) -> bool {
+        #[cfg(feature = "stats")]
+        self.stats.count_comparison();
+        let ordering = compare_with(self.comparator, self.id_fn, &self.heap[a], &self.heap[b]);
+        debug_assert!(
+            self.comparator.is_none()
+                || ordering == compare_with(self.comparator, self.id_fn, &self.heap[b], &self.heap[a]).reverse(),
+            "inconsistent ordering: comparator is not antisymmetric for {:?} and {:?}",
+            self.heap[a],
+            self.heap[b]
+        );
+        ordering == Ordering::Greater
+    }
+
+    fn sift_up(&mut self) -> usize {
+        let mut steps = 0;
+        while let Some(parent_index) = self.parent() {
+            if self.outranks(self.index, parent_index) {
+                self.transpose(parent_index);
+                steps += 1;
+            } else {
+                break;
+            }
+        }
+        steps
+    }
+
+    fn restore(&mut self) -> usize {
+        match self.sift_up() {
+            0 => self.sift_down(),
+            steps => steps,
+        }
+    }
+
+    fn transpose(&mut self, dest: usize) {
+        #[cfg(feature = "stats")]
+        self.stats.count_swap();
+        *self.index_map.get_mut(&id_with(self.id_fn, &self.heap[self.index])).unwrap() = dest;
+        *self.index_map.get_mut(&id_with(self.id_fn, &self.heap[dest])).unwrap() = self.index;
+        self.heap.swap(self.index, dest);
+        self.heap[self.index].on_move(self.index);
+        self.heap[dest].on_move(dest);
+        self.index = dest;
+    }
+
+    /// Restores heap order over the whole array bottom-up in O(n), leaving `index` at the root.
+    fn sift_all(&mut self) -> usize {
+        let mut steps = 0;
+        for ix in (0..(self.heap.len() >> 1)).rev() {
+            self.index = ix;
+            steps += self.sift_down();
+        }
+        self.index = 0;
+        steps
+    }
+
+    fn sift_down(&mut self) -> usize {
+        let left_index = self.left_index();
+        let right_index = self.right_index();
+        match (left_index, right_index) {
+            (None, None) => 0,
+            (Some(left), Some(right)) => {
+                let max = if self.outranks(left, right) {
+                    left
+                } else {
+                    right
+                };
+                if self.outranks(max, self.index) {
+                    self.transpose(max);
+                    1 + self.sift_down()
+                } else {
+                    0
+                }
+            }
+            (Some(index), None) | (None, Some(index)) => {
+                if self.outranks(index, self.index) {
+                    self.transpose(index);
+                    1 + self.sift_down()
+                } else {
+                    0
+                }
+            }
+        }
+    }
+}
+
+/// Mutable access to a single element. Heap order is restored when the guard is dropped, unless
+/// the element was only ever read through it.
+#[must_use = "dropping the guard immediately makes it a no-op"]
+pub struct HeapItemRefMut<'a, T: HeapItem, S: BuildHasher = RandomState> {
+    view: HeapViewMut<'a, T, S>,
+    original_key: Key,
+    original_id: T::Id,
+    mutated: bool,
+}
+
+impl<'a, T: HeapItem, S: BuildHasher> HeapItemRefMut<'a, T, S> {
+    /// Restores heap order now rather than when the guard goes out of scope, releasing the
+    /// borrow of the heap early.
+    pub fn commit(mut self) {
+        self.restore();
+    }
+
+    fn restore(&mut self) {
+        if !self.mutated {
+            return;
+        }
+        self.mutated = false;
+        let span = op_span!("restore", self.view.heap.len());
+        let new_id = id_with(self.view.id_fn, &self.view.heap[self.view.index]);
+        let new_key = self.view.heap[self.view.index].key();
+        let (_, old_index) = self.view.index_map.remove_entry(&self.original_id).unwrap();
+        debug_assert_eq!(old_index, self.view.index);
+        self.view.index_map.insert(new_id, old_index);
+        self.view.heap[old_index].on_move(old_index);
+        let steps = if self.view.comparator.is_some() {
+            self.view.restore()
+        } else if self.original_key > new_key {
+            self.view.sift_down()
+        } else if self.original_key < new_key {
+            self.view.sift_up()
+        } else {
+            0
+        };
+        span.record_sift_steps(steps);
+        self.view.debug_assert_no_orphans(&[old_index, self.view.index]);
+    }
+}
+
+impl<'a, T: HeapItem, S: BuildHasher> Drop for HeapItemRefMut<'a, T, S> {
+    fn drop(&mut self) {
+        self.restore();
+    }
+}
+
+pub struct HeapPairRefMut<'a, T: HeapItem, S: BuildHasher = RandomState> {
+    view: HeapViewMut<'a, T, S>,
+    indices: (usize, usize),
+    original_ids: (T::Id, T::Id),
+    original_keys: (Key, Key),
+}
+
+impl<'a, T: HeapItem, S: BuildHasher> HeapPairRefMut<'a, T, S> {
+    pub fn pair_mut(&mut self) -> (&mut T, &mut T) {
+        let (a, b) = self.indices;
+        let (head, tail) = self.view.heap.split_at_mut(a.max(b));
+        if a < b {
+            (&mut head[a], &mut tail[0])
+        } else {
+            (&mut tail[0], &mut head[b])
+        }
+    }
+}
+
+impl<'a, T: HeapItem, S: BuildHasher> Drop for HeapPairRefMut<'a, T, S> {
+    fn drop(&mut self) {
+        let (a, b) = self.indices;
+        let new_ids = (id_with(self.view.id_fn, &self.view.heap[a]), id_with(self.view.id_fn, &self.view.heap[b]));
+        self.view.index_map.remove(&self.original_ids.0);
+        self.view.index_map.remove(&self.original_ids.1);
+        self.view.index_map.insert(new_ids.0, a);
+        self.view.index_map.insert(new_ids.1.clone(), b);
+        self.view.heap[a].on_move(a);
+        self.view.heap[b].on_move(b);
+        let changed = (
+            self.view.heap[a].key() != self.original_keys.0,
+            self.view.heap[b].key() != self.original_keys.1,
+        );
+        match changed {
+            _ if self.view.comparator.is_some() => {
+                self.view.sift_all();
+            }
+            (true, true) => {
+                self.view.sift_all();
+            }
+            (true, false) => {
+                self.view.index = a;
+                self.view.restore();
+            }
+            (false, true) => {
+                self.view.index = b;
+                self.view.restore();
+            }
+            (false, false) => {}
+        }
+        self.view.debug_assert_no_orphans(&[a, b, self.view.index]);
+    }
+}
+
+impl<'a, T: HeapItem, S: BuildHasher> Deref for HeapItemRefMut<'a, T, S> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        self.view.heap.get(self.view.index).unwrap()
+    }
+}
+
+impl<'a, T: HeapItem, S: BuildHasher> DerefMut for HeapItemRefMut<'a, T, S> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.mutated = true;
+        self.view.heap.get_mut(self.view.index).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::BuildHasherDefault;
+    use super::*;
+
+    fn check_invariants<T: HeapItem, S: BuildHasher>(heap: &Heap<T, S>) {
+        let below = |child: &T, parent: &T| compare_with(&heap.comparator, &heap.id_fn, child, parent) != Ordering::Greater;
+        for i in 0..heap.len() {
+            let view = heap.get_view_at(i);
+            if let Some(left) = view.left() {
+                assert!(below(left.value(), view.value()));
+            }
+            if let Some(right) = view.right() {
+                assert!(below(right.value(), view.value()));
+            }
+        }
+        let view = heap.get_view();
+        for (id, index) in view.index_map.iter() {
+            assert_eq!(id_with(&heap.id_fn, &view.heap[*index]), *id);
+        }
+    }
+
+    #[test]
+    fn test_heapify() {
+        let heap = Heap::heapify(vec![9, 8, 7, 6, 5, 4, 3, 2, 1]);
+        check_invariants(&heap);
+    }
+
+    #[test]
+    fn with_comparator_selected_at_runtime() {
+        let make_heap = |ascending: bool| {
+            let mut heap = if ascending {
+                Heap::with_comparator(|a: &i64, b: &i64| b.cmp(a))
+            } else {
+                Heap::with_comparator(|a: &i64, b: &i64| a.cmp(b))
+            };
+            for &num in &[0, 100, 9, 41, -10, 55] {
+                heap.push(num);
+                check_invariants(&heap);
+            }
+            heap
+        };
+        let mut ascending = make_heap(true);
+        assert_eq!(ascending.drain_while(|_| true), vec![-10, 0, 9, 41, 55, 100]);
+        let descending = make_heap(false);
+        let mut cloned = descending.clone();
+        assert_eq!(cloned.drain_while(|_| true), vec![100, 55, 41, 9, 0, -10]);
+        assert_eq!(descending.len(), 6);
+    }
+
+    #[test]
+    fn replace_comparator() {
+        let mut heap = Heap::heapify(vec![0, 100, 9, 41, -10, 55]);
+        heap.replace_comparator(|a: &i64, b: &i64| b.cmp(a));
+        check_invariants(&heap);
+        assert_eq!(heap.pop(), Some(-10));
+        heap.replace_comparator(|a: &i64, b: &i64| (a % 2 == 0).cmp(&(b % 2 == 0)).then(a.cmp(b)));
+        check_invariants(&heap);
+        assert_eq!(heap.drain_while(|_| true), vec![100, 0, 55, 41, 9]);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "inconsistent ordering")]
+    fn inconsistent_comparator_panics() {
+        let mut heap = Heap::with_comparator(|_: &i64, _: &i64| Ordering::Greater);
+        heap.push(1);
+        heap.push(2);
+    }
+
+    #[test]
+    fn comparator_guard_restores_invariants() {
+        let mut heap = Heap::heapify_with_comparator(vec![0, 100, 9, 41, -10, 55], |a: &i64, b: &i64| b.cmp(a));
+        check_invariants(&heap);
+        assert_eq!(heap.peek(), Some(&-10));
+        *heap.get_mut(-10).unwrap() = 200;
+        check_invariants(&heap);
+        *heap.get_mut(100).unwrap() = -50;
+        check_invariants(&heap);
+        assert_eq!(heap.peek(), Some(&-50));
+    }
+
+    #[test]
+    fn merge_sorted_runs() {
+        let runs = vec![vec![100, 50, 3], vec![], vec![99, 98, 97, -1], vec![7, 6]];
+        let mut heap = Heap::merge_sorted_runs(runs).unwrap();
+        check_invariants(&heap);
+        assert_eq!(heap.len(), 9);
+        assert_eq!(heap.drain_while(|_| true), vec![100, 99, 98, 97, 50, 7, 6, 3, -1]);
+        let duplicated = Heap::merge_sorted_runs(vec![vec![5, 4], vec![6, 4]]);
+        assert_eq!(duplicated.unwrap_err(), HeapError::DuplicateId(4));
+    }
+
+    #[test]
+    fn from_sorted_descending() {
+        let mut heap = Heap::from_sorted_descending(vec![100, 55, 41, 9, 0, -10]);
+        check_invariants(&heap);
+        #[cfg(feature = "stats")]
+        assert_eq!(heap.stats(), Stats::default());
+        assert_eq!(heap.drain_while(|_| true), vec![100, 55, 41, 9, 0, -10]);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "sorted by descending key")]
+    fn from_sorted_descending_checks_order() {
+        Heap::from_sorted_descending(vec![1, 2]);
+    }
+
+    #[test]
+    fn collect_and_extend_reserve_up_front() {
+        let heap: Heap<i64> = (0..10000).collect();
+        check_invariants(&heap);
+        assert_eq!(heap.peek(), Some(&9999));
+        assert!(heap.capacity() >= 10000 && heap.capacity() < 20000);
+
+        let mut heap = Heap::with_capacity(0);
+        heap.extend(0..10000);
+        check_invariants(&heap);
+        assert_eq!(heap.len(), 10000);
+        assert!(heap.capacity() >= 10000 && heap.capacity() < 20000);
+
+        heap.extend(vec![-1, 20000, 15000]);
+        check_invariants(&heap);
+        assert_eq!(heap.peek(), Some(&20000));
+        assert_eq!(heap.len(), 10003);
+    }
+
+    #[test]
+    fn heapify_from_iter() {
+        let heap = Heap::heapify_from_iter((0..100).map(|x| x * 2));
+        check_invariants(&heap);
+        assert_eq!(heap.len(), 100);
+        assert_eq!(heap.peek(), Some(&198));
+    }
+
+    #[test]
+    fn try_from_iter() {
+        let heap = Heap::try_from_iter((0..10).map(|x| x * 3)).unwrap();
+        check_invariants(&heap);
+        assert_eq!(heap.peek(), Some(&27));
+        let duplicated = Heap::try_from_iter(vec![1, 5, 3, 5]);
+        assert_eq!(duplicated.unwrap_err(), HeapError::DuplicateId(5));
+    }
+
+    #[cfg(feature = "stats")]
+    #[test]
+    fn heapify_stats_within_bound() {
+        let n = 1000;
+        let heap = Heap::heapify((0..n).collect());
+        let stats = heap.stats();
+        assert!(stats.comparisons > 0 && stats.comparisons <= 2 * n as usize);
+        assert!(stats.swaps > 0 && stats.swaps <= n as usize);
+
+        let heap = Heap::heapify((0..n).rev().collect());
+        assert_eq!(heap.stats(), Stats { comparisons: n as usize - 1, swaps: 0 });
+        heap.reset_stats();
+        assert_eq!(heap.stats(), Stats::default());
+    }
+
+    #[test]
+    fn test_value() {
+        let heap = Heap::heapify(vec![9, 8, 7, 6, 5, 4, 3, 2, 1]);
+        check_invariants(&heap);
+        let view = heap.get_view();
+        assert_eq!(view.value(), &9);
+    }
+
+    #[test]
+    fn left_works() {
+        let heap = Heap::heapify(vec![9, 8, 7, 6, 5, 4, 3, 2, 1]);
+        let view = heap.get_view();
+        assert_eq!(view.left().unwrap().value(), &8);
+        assert_eq!(view.left().unwrap().left().unwrap().value(), &6);
+        assert_eq!(view.left().unwrap().left().unwrap().left().unwrap().value(), &2);
+        assert_eq!(view.left().unwrap().left().unwrap().left().unwrap().left(), None);
+    }
+
+    #[test]
+    fn right_works() {
+        let heap = Heap::heapify(vec![9, 8, 7, 6, 5, 4, 3, 2, 1]);
+        let view = heap.get_view();
+        assert_eq!(view.right().unwrap().value(), &7);
+        assert_eq!(view.right().unwrap().right().unwrap().value(), &3);
+        assert_eq!(view.right().unwrap().right().unwrap().right(), None);
+    }
+
+    #[test]
+    fn left_and_right_navigation() {
+        let heap = Heap::heapify(vec![9, 8, 7, 6, 5, 4, 3, 2, 1]);
+        let view = heap.get_view();
+        assert_eq!(view.left().unwrap().left().unwrap().right().unwrap().value(), &1);
+    }
+
+    #[test]
+    fn parent_works() {
+        let heap = Heap::heapify(vec![9, 8, 7, 6, 5, 4, 3, 2, 1]);
+        let view = heap.get_view();
+        assert_eq!(view.parent(), None);
+        assert_eq!(view.left().unwrap().parent().unwrap().value(), &9);
+        assert_eq!(view.right().unwrap().parent().unwrap().value(), &9);
+        assert_eq!(view.right().unwrap().left().unwrap().parent().unwrap().value(), &7);
+    }
+
+    #[test]
+    fn with_hasher_is_deterministic() {
+        type FixedState = BuildHasherDefault<DefaultHasher>;
+        let build = || {
+            let mut heap = Heap::heapify_with_hasher(vec![0, 100, 9, 41, -10, 55], FixedState::default());
+            heap.push(12);
+            heap.pop();
+            heap
+        };
+        let (first, second) = (build(), build());
+        check_invariants(&first);
+        assert_eq!(first.heap, second.heap);
+        assert_eq!(
+            first.index_map.iter().collect::<Vec<_>>(),
+            second.index_map.iter().collect::<Vec<_>>()
+        );
+
+        let mut empty = Heap::with_hasher(FixedState::default());
+        empty.push(3);
+        empty.push(7);
+        check_invariants(&empty);
+        assert_eq!(empty.peek(), Some(&7));
+    }
+
+    #[test]
+    fn is_heap_validates_slices() {
+        assert!(is_heap::<i64>(&[]));
+        assert!(is_heap(&[5]));
+        assert!(is_heap(&[9, 8, 7, 6, 5, 4, 3, 2, 1]));
+        assert!(is_heap(&[9, 1, 8, 0, 1, 7]));
+        assert!(!is_heap(&[1, 2]));
+        assert!(!is_heap(&[9, 8, 7, 6, 5, 4, 3, 2, 10]));
+        assert!(is_heap(&Heap::heapify(vec![0, 100, 9, 41, -10, 55]).heap));
+    }
+
+    #[test]
+    fn parts_round_trip() {
+        let heap = Heap::heapify(vec![0, 100, 9, 41, -10, 55]);
+        let (items, index_map) = heap.into_parts();
+        let mut heap = Heap::try_from_parts(items, index_map).unwrap();
+        check_invariants(&heap);
+        assert_eq!(heap.pop(), Some(100));
+
+        let (items, mut index_map) = heap.into_parts();
+        *index_map.get_mut(&55).unwrap() += 1;
+        assert_eq!(Heap::try_from_parts(items.clone(), index_map).unwrap_err(), HeapError::InconsistentIndexMap);
+        let mut reversed = items;
+        reversed.reverse();
+        let index_map: HashMap<_, _> = reversed.iter().enumerate().map(|(i, val)| (val.id(), i)).collect();
+        assert_eq!(Heap::try_from_parts(reversed, index_map).unwrap_err(), HeapError::NotHeapOrdered);
+    }
+
+    #[test]
+    fn heapify_in_place_orders_slices() {
+        for len in 0..20 {
+            let mut items: Vec<i64> = (0..len).map(|x| (x * 7) % 11).collect();
+            heapify_in_place(&mut items);
+            assert!(is_heap(&items));
+        }
+        let mut items = [0, 100, 9, 41, -10, 55];
+        heapify_in_place(&mut items[1..]);
+        assert!(is_heap(&items[1..]));
+        assert_eq!(items[..2], [0, 100]);
+    }
+
+    #[test]
+    fn capacity_for() {
+        assert!(Heap::<i64>::with_capacity(Heap::<i64>::capacity_for(100)).capacity() >= 100);
+        for n in 0..300 {
+            assert!(Heap::<i64>::capacity_for(n) >= n);
+        }
+        let mut heap = Heap::with_capacity(20);
+        let capacity = heap.capacity();
+        for num in 0..capacity as i64 {
+            heap.push(num);
+        }
+        assert_eq!(heap.capacity(), capacity);
+    }
+
+    #[test]
+    fn occupancy() {
+        let mut heap = Heap::with_capacity(10);
+        assert_eq!(heap.occupancy(), (0, Heap::<i64>::capacity_for(10)));
+        for num in 0..3 {
+            heap.push(num);
+        }
+        assert_eq!(heap.occupancy(), (3, Heap::<i64>::capacity_for(10)));
+    }
+
+    #[test]
+    fn capacity_policy() {
+        let mut heap = Heap::heapify(vec![]);
+        heap.set_len_capacity_policy(CapacityPolicy::Fixed(1000));
+        let mut capacities = vec![];
+        for num in 0..2500 {
+            heap.push(num);
+            if capacities.last() != Some(&heap.heap.capacity()) {
+                capacities.push(heap.heap.capacity());
+            }
+        }
+        assert_eq!(capacities, vec![1000, 2000, 3000]);
+        heap.reserve(1501);
+        assert_eq!(heap.heap.capacity(), 5000);
+        check_invariants(&heap);
+
+        let mut heap = Heap::heapify(vec![]);
+        heap.set_len_capacity_policy(CapacityPolicy::GoldenRatio);
+        let mut capacities = vec![];
+        for num in 0..50 {
+            heap.push(num);
+            if capacities.last() != Some(&heap.heap.capacity()) {
+                capacities.push(heap.heap.capacity());
+            }
+        }
+        assert_eq!(capacities, vec![4, 6, 9, 14, 22, 35, 56]);
+
+        let mut heap = Heap::heapify(vec![]);
+        heap.set_len_capacity_policy(CapacityPolicy::GoldenRatio);
+        let mut previous = 0;
+        for num in 0..1000 {
+            heap.push(num);
+            let capacity = heap.heap.capacity();
+            assert!(capacity == previous || previous == 0 || capacity <= previous * 13 / 8 + 1);
+            previous = capacity;
+        }
+    }
+
+    #[test]
+    fn try_reserve() {
+        let mut heap = Heap::heapify(vec![1, 2, 3]);
+        assert!(heap.try_reserve(100).is_ok());
+        assert!(heap.capacity() >= 103);
+        assert!(heap.try_reserve(usize::MAX).is_err());
+        heap.push(4);
+        check_invariants(&heap);
+
+        let mut heap = Heap::<i64>::heapify(vec![]);
+        heap.set_len_capacity_policy(CapacityPolicy::Fixed(1000));
+        assert!(heap.try_reserve(10).is_ok());
+        assert_eq!(heap.heap.capacity(), 1000);
+        assert!(heap.index_map.capacity() >= 10);
+        assert!(heap.try_reserve(1500).is_ok());
+        assert_eq!(heap.heap.capacity(), 2000);
+        assert!(heap.try_reserve(usize::MAX).is_err());
+    }
+
+    #[test]
+    fn try_extend() {
+        for &(existing, batch) in &[(20, 5), (5, 20), (0, 3)] {
+            let mut heap = Heap::heapify((0..existing).map(|x| x * 2).collect());
+            let mut naive = heap.clone();
+            let items: Vec<_> = (0..batch).map(|x| (x * 7) % batch * 2 + 1).collect();
+            heap.try_extend(items.clone()).unwrap();
+            for item in items {
+                naive.push(item);
+            }
+            check_invariants(&heap);
+            assert_eq!(heap.into_sorted_vec(), naive.into_sorted_vec());
+        }
+
+        let mut heap = Heap::heapify(vec![4, 2]);
+        assert_eq!(heap.try_extend(vec![3, 2]), Err(HeapError::DuplicateId(2)));
+        assert_eq!(heap.try_extend(vec![7, 7]), Err(HeapError::DuplicateId(7)));
+        assert_eq!(heap.len(), 2);
+    }
+
+    #[test]
+    fn grow_from_slice() {
+        let source = [5, 50, -5, 7];
+        let mut heap = Heap::heapify(vec![1, 2]);
+        heap.grow_from_slice(&source).unwrap();
+        check_invariants(&heap);
+        assert_eq!(source, [5, 50, -5, 7]);
+        assert_eq!(heap.len(), 6);
+        assert_eq!(heap.grow_from_slice(&[3, 50]), Err(HeapError::DuplicateId(50)));
+        assert_eq!(heap.grow_from_slice(&[3, 3]), Err(HeapError::DuplicateId(3)));
+        assert_eq!(heap.len(), 6);
+        heap.grow_from_slice(&[60]).unwrap();
+        assert_eq!(heap.into_sorted_vec(), vec![60, 50, 7, 5, 2, 1, -5]);
+    }
+
+    #[test]
+    fn auto_shrink() {
+        let mut heap = Heap::heapify((0..1000).collect());
+        let full_capacity = heap.capacity();
+        heap.drain_while(|&num| num >= 100);
+        assert_eq!(heap.capacity(), full_capacity);
+
+        heap.set_auto_shrink(true);
+        heap.drain_while(|&num| num >= 10);
+        check_invariants(&heap);
+        assert!(heap.capacity() < full_capacity / 4);
+        assert!(heap.capacity() >= heap.len());
+    }
+
+    #[test]
+    fn singleton() {
+        let heap = Heap::singleton(42);
+        check_invariants(&heap);
+        assert_eq!(heap.peek(), Some(&42));
+        assert_eq!(heap.len(), 1);
+    }
+
+    #[test]
+    fn front_aliases() {
+        let mut heap = Heap::heapify(vec![3, 17, 5, 11]);
+        let mut canonical = Heap::heapify(vec![3, 17, 5, 11]);
+        while !heap.is_empty() {
+            assert_eq!(heap.front(), canonical.peek());
+            assert_eq!(heap.pop_front(), canonical.pop());
+        }
+        assert_eq!(heap.front(), None);
+        assert_eq!(heap.pop_front(), None);
+    }
+
+    #[test]
+    fn peek_at_depth() {
+        let heap = Heap::heapify(vec![9, 8, 7, 6, 5, 4, 3, 2, 1]);
+        assert_eq!(heap.peek_at_depth(0).collect::<Vec<_>>(), vec![&9]);
+        assert_eq!(heap.peek_at_depth(1).collect::<Vec<_>>(), vec![&8, &7]);
+        assert_eq!(heap.peek_at_depth(3).collect::<Vec<_>>(), vec![&2, &1]);
+        assert_eq!(heap.peek_at_depth(4).count(), 0);
+        assert_eq!(heap.peek_at_depth(usize::MAX).count(), 0);
+        let heap = Heap::heapify(vec![3, 17]);
+        assert_eq!(heap.peek_at_depth(1).collect::<Vec<_>>(), vec![&3]);
+    }
+
+    #[test]
+    fn iter_with_index() {
+        let heap = Heap::heapify(vec![0, 100, 9, 41, -10, 55]);
+        let pairs: Vec<_> = heap.iter_with_index().collect();
+        assert_eq!(pairs[0], (0, &100));
+        assert_eq!(pairs.len(), 6);
+        assert!(pairs.iter().all(|&(index, item)| heap.key_at_index(index) == Some(*item)));
+    }
+
+    #[test]
+    fn key_at_index() {
+        let heap = Heap::heapify(vec![0, 100, 9, 41, -10, 55]);
+        assert_eq!(heap.key_at_index(0), Some(100));
+        assert!((1..6).all(|index| heap.key_at_index(index) <= heap.key_at_index((index - 1) / 2)));
+        assert_eq!(heap.key_at_index(6), None);
+    }
+
+    #[test]
+    fn iter_leaves() {
+        let heap = Heap::heapify(vec![9, 8, 7, 6, 5, 4, 3, 2, 1]);
+        let leaves: Vec<_> = heap.iter_leaves().copied().collect();
+        assert_eq!(leaves, heap.heap[4..]);
+        assert_eq!(leaves.len(), 5);
+        assert!(leaves.contains(&1));
+        assert_eq!(Heap::singleton(3).iter_leaves().collect::<Vec<_>>(), vec![&3]);
+        assert_eq!(Heap::<i64>::heapify(vec![]).iter_leaves().count(), 0);
+    }
+
+    #[cfg(feature = "rand")]
+    #[test]
+    fn sample_uniform() {
+        use rand::rngs::StdRng;
+        use rand::SeedableRng;
+
+        let heap = Heap::heapify((0..50).collect());
+        let picks = |seed| {
+            let mut rng = StdRng::seed_from_u64(seed);
+            (0..10).map(|_| *heap.sample_uniform(&mut rng).unwrap()).collect::<Vec<_>>()
+        };
+        assert_eq!(picks(7), picks(7));
+        assert!(picks(7).iter().all(|pick| heap.get(*pick).is_some()));
+        let distinct: HashSet<_> = picks(7).into_iter().collect();
+        assert!(distinct.len() > 1);
+        assert!(Heap::<i64>::heapify(vec![]).sample_uniform(&mut StdRng::seed_from_u64(7)).is_none());
+    }
+
+    #[cfg(feature = "rand")]
+    #[test]
+    fn weighted_sample() {
+        use rand::rngs::StdRng;
+        use rand::SeedableRng;
+
+        let heap = Heap::heapify(vec![1, 2, 3, 4, 0, -5]);
+        let mut rng = StdRng::seed_from_u64(42);
+        let draws = 40_000;
+        let mut counts = HashMap::new();
+        for _ in 0..draws {
+            *counts.entry(*heap.weighted_sample(&mut rng).unwrap()).or_insert(0) += 1;
+        }
+        assert_eq!(counts.get(&0), None);
+        assert_eq!(counts.get(&-5), None);
+        for key in 1..=4 {
+            let observed = f64::from(counts[&key]) / f64::from(draws);
+            assert!((observed - key as f64 / 10.0).abs() < 0.02, "{} {}", key, observed);
+        }
+        assert!(Heap::heapify(vec![0, -1]).weighted_sample(&mut rng).is_none());
+    }
+
+    #[test]
+    fn peek_key() {
+        let mut heap = Heap::heapify(vec![3, 17, 5, 11]);
+        assert_eq!(heap.peek_key(), Some(17));
+        heap.pop();
+        assert_eq!(heap.peek_key(), Some(11));
+        assert_eq!(Heap::<i64>::heapify(vec![]).peek_key(), None);
+    }
+
+    #[test]
+    fn id_set_and_key_range() {
+        let heap = Heap::heapify(vec![0, 100, 9, 41, -10, 55]);
+        assert_eq!(heap.id_set(), vec![0, 100, 9, 41, -10, 55].into_iter().collect());
+        assert_eq!(heap.key_range(), Some((-10, 100)));
+        assert_eq!(Heap::singleton(4).key_range(), Some((4, 4)));
+        let empty = Heap::<i64>::heapify(vec![]);
+        assert!(empty.id_set().is_empty());
+        assert_eq!(empty.key_range(), None);
+    }
+
+    #[test]
+    fn at_least_n_ge() {
+        let heap = Heap::heapify((0..200).map(|x| (x * 37) % 200 - 100).collect());
+        for &threshold in &[-150, -100, -1, 0, 50, 99, 100] {
+            let expected = heap.heap.iter().filter(|&&x| x >= threshold).count();
+            for n in &[0, 1, expected.saturating_sub(1), expected, expected + 1] {
+                assert_eq!(heap.at_least_n_ge(threshold, *n), *n <= expected, "{} {}", threshold, n);
+            }
+        }
+        assert!(!Heap::<i64>::heapify(vec![]).at_least_n_ge(0, 1));
+    }
+
+    #[cfg(feature = "stats")]
+    #[test]
+    fn at_least_n_ge_stops_early() {
+        let heap = Heap::heapify((0..1000).collect());
+        heap.reset_stats();
+        assert!(heap.at_least_n_ge(0, 3));
+        assert_eq!(heap.stats().comparisons, 3);
+    }
+
+    #[test]
+    fn ids_at_least() {
+        let heap = Heap::heapify((0..200).map(|x| (x * 37) % 200 - 100).collect());
+        for &threshold in &[-150, -1, 0, 50, 99, 100] {
+            let found: HashSet<Id> = heap.ids_at_least(threshold).into_iter().collect();
+            let expected: HashSet<Id> = heap.heap.iter().copied().filter(|&x| x >= threshold).collect();
+            assert_eq!(found, expected);
+        }
+    }
+
+    #[test]
+    fn next_n_keys() {
+        let heap = Heap::heapify((0..100).map(|x| (x * 37) % 101 - 50).collect());
+        let sorted = heap.clone().into_sorted_vec();
+        for n in [0, 1, 5, 50, 100, 150] {
+            assert_eq!(heap.next_n_keys(n), sorted[..n.min(100)]);
+        }
+        assert_eq!(heap.len(), 100);
+        assert!(Heap::<i64>::heapify(vec![]).next_n_keys(3).is_empty());
+    }
+
+    #[test]
+    fn rank_of_key() {
+        let heap = Heap::heapify((0..100).map(|x| (x * 37) % 101 - 50).collect());
+        let sorted = heap.clone().into_sorted_vec();
+        for (position, &key) in sorted.iter().enumerate() {
+            assert_eq!(heap.rank_of_key(key), position);
+        }
+        assert_eq!(heap.rank_of_key(1000), 0);
+        assert_eq!(heap.rank_of_key(Key::MAX), 0);
+        assert_eq!(heap.rank_of_key(-1000), 100);
+    }
+
+    #[test]
+    fn key_queries_under_ascending_comparator() {
+        let keys: Vec<i64> = (0..100).map(|x| (x * 37) % 101 - 50).collect();
+        let heap = Heap::heapify_with_comparator(keys.clone(), |a: &i64, b: &i64| b.cmp(a));
+        for &threshold in &[-60, -50, -1, 0, 25, 50, 60] {
+            let expected: HashSet<Id> = keys.iter().copied().filter(|&x| x >= threshold).collect();
+            let found: HashSet<Id> = heap.ids_at_least(threshold).into_iter().collect();
+            assert_eq!(found, expected);
+            assert!(heap.at_least_n_ge(threshold, expected.len()));
+            assert!(!heap.at_least_n_ge(threshold, expected.len() + 1));
+            assert_eq!(heap.rank_of_key(threshold), keys.iter().filter(|&&x| x > threshold).count());
+        }
+        assert_eq!(heap.key_range(), Some((*keys.iter().min().unwrap(), *keys.iter().max().unwrap())));
+    }
+
+    #[test]
+    fn any_ge() {
+        let heap = Heap::heapify(vec![3, 17, 5, 11]);
+        assert!(heap.any_ge(16));
+        assert!(heap.any_ge(17));
+        assert!(!heap.any_ge(18));
+        assert!(!Heap::<i64>::heapify(vec![]).any_ge(Key::MIN));
+    }
+
+    #[test]
+    fn rebuild_index_map() {
+        let mut heap = Heap::heapify(vec![0, 100, 9, 41, -10, 55]);
+        for index in heap.index_map.values_mut() {
+            *index = 0;
+        }
+        heap.index_map.remove(&9);
+        heap.index_map.insert(1000, 3);
+        heap.rebuild_index_map();
+        check_invariants(&heap);
+        assert_eq!(heap.index_map.len(), 6);
+    }
+
+    #[test]
+    fn swap_with_vec() {
+        let mut heap = Heap::heapify(vec![0, 100, 9, 41]);
+        let mut buffer = Vec::with_capacity(16);
+        buffer.extend(vec![5, 50, -5]);
+        heap.swap_with_vec(&mut buffer).unwrap();
+        check_invariants(&heap);
+        assert_eq!(heap.len(), 3);
+        assert!(heap.get(100).is_none());
+        assert!(heap.capacity() >= 3);
+        buffer.sort_unstable();
+        assert_eq!(buffer, vec![0, 9, 41, 100]);
+
+        let mut duplicated = vec![1, 2, 1];
+        assert_eq!(heap.swap_with_vec(&mut duplicated), Err(HeapError::DuplicateId(1)));
+        assert_eq!(duplicated, vec![1, 2, 1]);
+        assert_eq!(heap.into_sorted_vec(), vec![50, 5, -5]);
+    }
+
+    #[test]
+    fn rebuild() {
+        let mut heap = Heap::heapify(vec![0, 100, 9, 41, -10, 55]);
+        heap.heap.reverse();
+        heap.index_map.clear();
+        heap.rebuild();
+        check_invariants(&heap);
+        assert_eq!(heap.pop(), Some(100));
+    }
+
+    #[test]
+    fn contains_any_and_all() {
+        let heap = Heap::heapify(vec![0, 100, 9, 41, -10, 55]);
+        assert!(heap.contains(41) && !heap.contains(42));
+        assert!(heap.contains_any(&[1, 2, 9]));
+        assert!(!heap.contains_all(&[1, 2, 9]));
+        assert!(heap.contains_all(&[100, -10, 0]));
+        assert!(heap.contains_any(&[100, -10, 0]));
+        assert!(!heap.contains_any(&[1, 2, 3]));
+        assert!(!heap.contains_any(&[]));
+        assert!(heap.contains_all(&[]));
+    }
+
+    #[test]
+    fn bulk_remove() {
+        let mut heap = Heap::heapify((0..20).collect());
+        let mut removed = heap.bulk_remove(&[3, 19, 0, 7, 7, 100]);
+        removed.sort();
+        assert_eq!(removed, vec![0, 3, 7, 19]);
+        check_invariants(&heap);
+        assert_eq!(heap.len(), 16);
+        for id in &[0, 3, 7, 19] {
+            assert!(heap.get(*id).is_none());
+        }
+        assert_eq!(heap.pop(), Some(18));
+    }
+
+    #[test]
+    fn peak_len() {
+        let mut heap = Heap::heapify(vec![1, 2, 3]);
+        assert_eq!(heap.peak_len(), 3);
+        for num in 4..=8 {
+            heap.push(num);
+        }
+        heap.pop();
+        heap.pop();
+        assert_eq!((heap.len(), heap.peak_len()), (6, 8));
+        heap.extend(vec![20, 21, 22]);
+        heap.truncate_to_len(2);
+        assert_eq!(heap.peak_len(), 9);
+        heap.reset_peak_len();
+        assert_eq!(heap.peak_len(), 2);
+        heap.swap_with_vec(&mut vec![1, 2, 3, 4]).unwrap();
+        assert_eq!(heap.peak_len(), 4);
+    }
+
+    #[test]
+    fn saturating_push() {
+        let mut heap = Heap::heapify(vec![]);
+        heap.set_max_len(Some(3));
+        assert!(heap.saturating_push(1));
+        assert!(heap.saturating_push(5));
+        assert!(heap.saturating_push(3));
+        assert!(!heap.saturating_push(10));
+        assert!(!heap.saturating_push(0));
+        check_invariants(&heap);
+        assert_eq!(heap.len(), 3);
+        assert_eq!(heap.peek(), Some(&5));
+        heap.pop();
+        assert!(heap.saturating_push(10));
+        heap.set_max_len(None);
+        assert!(heap.saturating_push(0));
+        assert_eq!(heap.len(), 4);
+    }
+
+    #[test]
+    fn split_at_key() {
+        let heap = Heap::heapify(vec![0, 100, 9, 41, -10, 55, 12]);
+        let (high, low) = heap.split_at_key(12);
+        check_invariants(&high);
+        check_invariants(&low);
+        assert_eq!(high.into_sorted_vec(), vec![100, 55, 41, 12]);
+        assert_eq!(low.into_sorted_vec(), vec![9, 0, -10]);
+
+        let (high, low) = Heap::heapify(vec![1, 2]).split_at_key(10);
+        assert!(high.is_empty());
+        assert_eq!(low.len(), 2);
+    }
+
+    #[test]
+    fn drain_bottom_n() {
+        let mut heap = Heap::heapify(vec![0, 100, 9, 41, -10, 55, 12]);
+        let mut removed = heap.drain_bottom_n(3);
+        removed.sort_unstable();
+        assert_eq!(removed, vec![-10, 0, 9]);
+        check_invariants(&heap);
+        assert_eq!(heap.clone().into_sorted_vec(), vec![100, 55, 41, 12]);
+        assert!(heap.drain_bottom_n(0).is_empty());
+        assert_eq!(heap.drain_bottom_n(10).len(), 4);
+        assert!(heap.is_empty());
+    }
+
+    #[test]
+    fn truncate_to_len() {
+        let mut heap = Heap::heapify((0..50).map(|x| (x * 37) % 50).collect());
+        heap.truncate_to_len(60);
+        assert_eq!(heap.len(), 50);
+        heap.truncate_to_len(10);
+        check_invariants(&heap);
+        assert_eq!(heap.index_map.len(), 10);
+        assert_eq!(heap.drain_while(|_| true), (40..50).rev().collect::<Vec<_>>());
+        heap.push(3);
+        heap.truncate_to_len(0);
+        assert!(heap.is_empty() && heap.index_map.is_empty());
+    }
+
+    #[test]
+    fn index_map_consistent() {
+        let mut heap = Heap::heapify(vec![0, 100, 9, 41, -10, 55]);
+        assert!(heap.index_map_consistent());
+        heap.heap.swap(0, 5);
+        assert!(!heap.index_map_consistent());
+        heap.rebuild_index_map();
+        assert!(heap.index_map_consistent() && !is_heap(&heap.heap));
+        heap.rebuild();
+        *heap.index_map.get_mut(&9).unwrap() = 17;
+        assert!(!heap.index_map_consistent());
+        heap.rebuild_index_map();
+        heap.index_map.remove(&9);
+        assert!(!heap.index_map_consistent());
+        heap.rebuild_index_map();
+        let (a, b) = (heap.index_map[&41], heap.index_map[&100]);
+        heap.index_map.insert(41, b);
+        heap.index_map.insert(100, a);
+        assert!(!heap.index_map_consistent());
+        assert!(!heap.repair().is_clean());
+        assert!(heap.index_map_consistent());
+    }
+
+    #[test]
+    fn repair() {
+        let mut heap = Heap::heapify(vec![0, 100, 9, 41, -10, 55]);
+        assert!(heap.repair().is_clean());
+
+        heap.index_map.insert(1000, 17);
+        heap.index_map.remove(&9);
+        *heap.index_map.get_mut(&41).unwrap() = heap.index_map[&100];
+        heap.heap.swap(0, 5);
+        let report = heap.repair();
+        assert_eq!(report, RepairReport {
+            dangling_entries: 1,
+            misdirected_entries: 2,
+            unmapped_elements: 1,
+            reordered: true,
+        });
+        check_invariants(&heap);
+        assert_eq!(heap.index_map.len(), 6);
+        assert!(heap.repair().is_clean());
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "index_map is out of sync")]
+    fn duplicate_id_edit_trips_orphan_check() {
+        let mut heap = Heap::heapify(vec![0, 100, 9, 41, -10, 55]);
+        *heap.get_mut(9).unwrap() = 41;
+    }
+
+    #[test]
+    fn replace_root() {
+        let mut heap = Heap::heapify(vec![0, 100, 9, 41, -10, 55]);
+        assert_eq!(heap.replace_root(20), Some(100));
+        check_invariants(&heap);
+        assert!(heap.get(100).is_none());
+        assert_eq!(heap.peek(), Some(&55));
+        assert_eq!(Heap::heapify(vec![]).replace_root(1), None);
+    }
+
+    #[test]
+    fn swap_remove_root_to() {
+        let mut heap = Heap::heapify(vec![0, 100, 9, 41, -10, 55]);
+        assert_eq!(heap.swap_remove_root_to(99), Some(100));
+        check_invariants(&heap);
+        assert_eq!(heap.peek(), Some(&99));
+        assert_eq!(heap.swap_remove_root_to(-5), Some(99));
+        check_invariants(&heap);
+        assert_eq!(heap.peek(), Some(&55));
+        assert_eq!(heap.len(), 6);
+        let mut empty = Heap::heapify(vec![]);
+        assert_eq!(empty.swap_remove_root_to(1), None);
+        assert_eq!(empty.peek(), Some(&1));
+    }
+
+    #[test]
+    fn remove() {
+        let mut heap = Heap::heapify((0..20).map(|x| (x * 7) % 20).collect());
+        for id in &[13, 0, 19, 5, 6, 4] {
+            assert_eq!(heap.remove(*id), Some(*id));
+            check_invariants(&heap);
+        }
+        assert_eq!(heap.remove(13), None);
+        assert_eq!(heap.len(), 14);
+    }
+
+    #[test]
+    fn pop_min() {
+        let mut heap = Heap::heapify(vec![0, 100, 9, 41, -10, 55, 12, 3]);
+        assert_eq!(heap.find_min_id(), Some(-10));
+        let mut popped = vec![];
+        while let Some(num) = heap.pop_min() {
+            check_invariants(&heap);
+            popped.push(num);
+        }
+        assert_eq!(popped, vec![-10, 0, 3, 9, 12, 41, 55, 100]);
+        assert_eq!(heap.find_min_id(), None);
+    }
+
+    #[test]
+    fn median_key() {
+        assert_eq!(Heap::<i64>::heapify(vec![]).median_key(), None);
+        for len in [1, 2, 3, 10, 51, 100] {
+            let heap = Heap::heapify((0..len).map(|x| (x * 37) % 101 - 50).collect());
+            let mut sorted = heap.heap.clone();
+            sorted.sort_unstable();
+            assert_eq!(heap.median_key(), Some(sorted[sorted.len() / 2]));
+            check_invariants(&heap);
+        }
+    }
+
+    #[test]
+    fn try_pop_min_if() {
+        let mut heap = Heap::heapify(vec![0, 100, 9, 41, -10, 55]);
+        assert_eq!(heap.try_pop_min_if(|t| t.key() < -20), None);
+        assert_eq!(heap.len(), 6);
+        assert_eq!(heap.try_pop_min_if(|t| t.key() < 5), Some(-10));
+        assert_eq!(heap.try_pop_min_if(|t| t.key() < 5), Some(0));
+        assert_eq!(heap.try_pop_min_if(|t| t.key() < 5), None);
+        check_invariants(&heap);
+        assert_eq!(heap.len(), 4);
+        assert_eq!(Heap::<i64>::heapify(vec![]).try_pop_min_if(|_| true), None);
+    }
+
+    #[test]
+    fn key_percentile() {
+        assert_eq!(Heap::<i64>::heapify(vec![]).key_percentile(0.5), None);
+        for len in [1, 2, 5, 10, 101] {
+            let heap = Heap::heapify((0..len).map(|x| (x * 37) % 101 - 50).collect());
+            let mut sorted = heap.heap.clone();
+            sorted.sort_unstable();
+            assert_eq!(heap.key_percentile(0.0), sorted.first().copied());
+            assert_eq!(heap.key_percentile(1.0), heap.peek_key());
+            assert_eq!(heap.key_percentile(0.5), heap.median_key());
+            assert_eq!(heap.key_percentile(-3.0), heap.key_percentile(0.0));
+            assert_eq!(heap.key_percentile(7.0), heap.key_percentile(1.0));
+            assert_eq!(heap.key_percentile(f64::NAN), heap.key_percentile(0.0));
+        }
+        let heap = Heap::heapify((1..=101).collect());
+        assert_eq!(heap.key_percentile(0.9), Some(91));
+    }
+
+    #[test]
+    fn total_key_sum() {
+        let heap = Heap::heapify(vec![Key::MAX, Key::MAX - 1, Key::MAX - 2, -5]);
+        assert_eq!(heap.total_key_sum(), 3 * i128::from(Key::MAX) - 8);
+        let heap = Heap::heapify(vec![Key::MIN, Key::MIN]);
+        assert_eq!(heap.total_key_sum(), 2 * i128::from(Key::MIN));
+        assert_eq!(Heap::<i64>::heapify(vec![]).total_key_sum(), 0);
+    }
+
+    #[test]
+    fn key_histogram() {
+        let heap = Heap::heapify(vec![0, 100, 9, 41, -10, 55, 45, 1, -1]);
+        let expected: BTreeMap<Key, usize> = vec![(-10, 2), (0, 3), (40, 2), (50, 1), (100, 1)].into_iter().collect();
+        assert_eq!(heap.key_histogram(10), expected);
+        assert!(Heap::<i64>::heapify(vec![]).key_histogram(5).is_empty());
+        let extremes = Heap::heapify(vec![i64::MIN, i64::MIN + 1, 5, i64::MAX]);
+        let expected: BTreeMap<Key, usize> =
+            vec![(i64::MIN, 2), (3, 1), (i64::MAX - 1, 1)].into_iter().collect();
+        assert_eq!(extremes.key_histogram(3), expected);
+    }
+
+    #[test]
+    fn merge_in_sorted_order() {
+        let mut heaps = vec![
+            Heap::heapify(vec![0, 100, 9, 41]),
+            Heap::heapify(vec![]),
+            Heap::heapify(vec![-10, 55, 12]),
+            Heap::heapify(vec![99, 1, 2, 3, 4]),
+        ];
+        let merged = Heap::merge_in_sorted_order(&mut heaps);
+        assert_eq!(merged, vec![100, 99, 55, 41, 12, 9, 4, 3, 2, 1, 0, -10]);
+        assert!(heaps.iter().all(Heap::is_empty));
+        heaps[1].push(7);
+        assert_eq!(heaps[1].pop(), Some(7));
+    }
+
+    #[test]
+    fn into_iter_unordered() {
+        let heap = Heap::heapify((0..50).map(|x| (x * 37) % 50).collect());
+        let mut items: Vec<_> = heap.into_iter_unordered().collect();
+        items.sort();
+        assert_eq!(items, (0..50).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn remove_subtree() {
+        let mut heap = Heap::heapify(vec![9, 8, 7, 6, 5, 4, 3, 2, 1]);
+        assert_eq!(heap.remove_subtree(8), vec![8, 6, 5, 2, 1]);
+        check_invariants(&heap);
+        assert_eq!(heap.drain_while(|_| true), vec![9, 7, 4, 3]);
+
+        let mut heap = Heap::heapify(vec![9, 8, 7, 6, 5, 4, 3, 2, 1]);
+        assert_eq!(heap.remove_subtree(1), vec![1]);
+        assert!(heap.remove_subtree(100).is_empty());
+        assert_eq!(heap.remove_subtree(9).len(), 8);
+        assert!(heap.is_empty());
+    }
+
+    #[test]
+    fn retain_ids() {
+        let mut heap = Heap::heapify((0..20).collect());
+        heap.retain_ids(|id| id % 2 == 0);
+        check_invariants(&heap);
+        assert_eq!(heap.len(), 10);
+        assert!((0..20).filter(|id| id % 2 == 1).all(|id| heap.get(id).is_none()));
+        assert_eq!(heap.pop(), Some(18));
+    }
+
+    #[test]
+    fn retain_and_collect() {
+        let mut heap = Heap::heapify((0..20).collect());
+        let mut removed = heap.retain_and_collect(|t| t.key() % 3 != 0);
+        removed.sort_unstable();
+        assert_eq!(removed, vec![0, 3, 6, 9, 12, 15, 18]);
+        check_invariants(&heap);
+        assert_eq!(heap.len(), 13);
+        assert!(removed.iter().all(|&id| heap.get(id).is_none()));
+        heap.retain(|t| t.key() > 10);
+        check_invariants(&heap);
+        assert_eq!(heap.drain_while(|_| true), vec![19, 17, 16, 14, 13, 11]);
+    }
+
+    #[test]
+    fn drain_sorted() {
+        let mut heap = Heap::heapify(vec![0, 100, 9, 41, -10, 55]);
+        let capacity = heap.capacity();
+        let mut drain = heap.drain_sorted();
+        assert_eq!(drain.len(), 6);
+        assert_eq!(drain.next(), Some(100));
+        assert_eq!(drain.next(), Some(55));
+        assert_eq!(drain.len(), 4);
+        drop(drain);
+        assert!(heap.is_empty() && heap.index_map.is_empty());
+        assert_eq!(heap.capacity(), capacity);
+        heap.push(3);
+        heap.push(4);
+        assert_eq!(heap.drain_sorted().collect::<Vec<_>>(), vec![4, 3]);
+        assert!(heap.is_empty());
+    }
+
+    #[test]
+    fn drain_sorted_into() {
+        let mut heap = Heap::heapify(vec![0, 100, 9, 41, -10, 55]);
+        let mut sink = vec![7, 7];
+        heap.drain_sorted_into(&mut sink);
+        assert_eq!(sink, vec![7, 7, 100, 55, 41, 9, 0, -10]);
+        assert!(heap.is_empty());
+        heap.push(3);
+        assert_eq!(heap.peek(), Some(&3));
+    }
+
+    #[test]
+    fn iter_sorted_by_key_desc_chunks() {
+        let heap = Heap::heapify((0..10).collect());
+        let pages: Vec<_> = heap.iter_sorted_by_key_desc_chunks(3).collect();
+        assert_eq!(pages, vec![vec![9, 8, 7], vec![6, 5, 4], vec![3, 2, 1], vec![0]]);
+        assert_eq!(heap.len(), 10);
+        assert_eq!(heap.iter_sorted_by_key_desc_chunks(20).count(), 1);
+        assert_eq!(Heap::<i64>::heapify(vec![]).iter_sorted_by_key_desc_chunks(3).count(), 0);
+    }
+
+    #[test]
+    fn fold_sorted() {
+        let heap = Heap::heapify(vec![0, 100, 9, 41, -10, 55]);
+        let (weighted, _) = heap.fold_sorted((0, 1), |(sum, weight), t| (sum + weight * t.key(), weight + 1));
+        let mut expected = 0;
+        for (weight, key) in (1..).zip(&[100, 55, 41, 9, 0, -10]) {
+            expected += weight * key;
+        }
+        assert_eq!(weighted, expected);
+        assert_eq!(heap.len(), 6);
+        check_invariants(&heap);
+    }
+
+    #[test]
+    fn into_sorted_vec() {
+        let heap = Heap::heapify(vec![0, 100, 9, 41, -10, 55]);
+        assert_eq!(heap.into_sorted_vec(), vec![100, 55, 41, 9, 0, -10]);
+    }
+
+    #[test]
+    fn clone_into_sorted_vec() {
+        let heap = Heap::heapify(vec![0, 100, 9, 41, -10, 55]);
+        let backing = heap.heap.clone();
+        assert_eq!(heap.clone_into_sorted_vec(), vec![100, 55, 41, 9, 0, -10]);
+        assert_eq!(heap.heap, backing);
+        check_invariants(&heap);
+        let ascending = Heap::heapify_with_comparator(vec![3, 1, 2], |a: &i64, b: &i64| b.cmp(a));
+        assert_eq!(ascending.clone_into_sorted_vec(), vec![1, 2, 3]);
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn par_into_sorted_vec() {
+        let heap = Heap::heapify((0..10_000).map(|x| (x * 7919) % 10_007).collect());
+        assert_eq!(heap.clone().par_into_sorted_vec(), heap.into_sorted_vec());
+        let ascending = Heap::heapify_with_comparator(vec![3, 1, 2], |a: &i64, b: &i64| b.cmp(a));
+        assert_eq!(ascending.par_into_sorted_vec(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn into_sorted_vec_ascending() {
+        let items: Vec<_> = (0..30).map(|x| (x * 17) % 31 - 15).collect();
+        let mut expected = items.clone();
+        expected.sort_unstable();
+        assert_eq!(Heap::heapify(items).into_sorted_vec_ascending(), expected);
+    }
+
+    #[test]
+    fn leak_sorted() {
+        let table: &'static [i64] = Heap::heapify(vec![0, 100, 9, 41, -10, 55]).leak_sorted();
+        assert_eq!(table, &[100, 55, 41, 9, 0, -10]);
+    }
+
+    #[test]
+    fn drain_while() {
+        let mut heap = Heap::heapify(vec![0, 100, 9, 41, -10, 55, 12]);
+        let drained = heap.drain_while(|t| t.key() > 10);
+        assert_eq!(drained, vec![100, 55, 41, 12]);
+        check_invariants(&heap);
+        assert_eq!(heap.len(), 3);
+        assert_eq!(heap.peek(), Some(&9));
+        assert_eq!(heap.drain_while(|_| true), vec![9, 0, -10]);
+        assert!(heap.is_empty());
+    }
+
+    #[test]
+    fn pop_ties() {
+        let jobs = [(1, 7), (2, 3), (3, 7), (4, 5), (5, 7), (6, 3)];
+        let mut job_queue: Heap<Job> = jobs
+            .iter()
+            .map(|&(id, priority)| Job {id, priority, description: String::new()})
+            .collect();
+        let mut ties: Vec<_> = job_queue.pop_ties().iter().map(|job| job.id).collect();
+        ties.sort_unstable();
+        assert_eq!(ties, vec![1, 3, 5]);
+        check_invariants(&job_queue);
+        assert_eq!(job_queue.pop_ties().len(), 1);
+        assert_eq!(job_queue.pop_ties().len(), 2);
+        assert!(job_queue.pop_ties().is_empty());
+    }
+
+    #[test]
+    fn pop_until_len() {
+        let mut heap = Heap::heapify(vec![3, 8, 1, 6, 10, 2, 7, 4, 9, 5]);
+        assert!(heap.pop_until_len(10).is_empty());
+        assert_eq!(heap.pop_until_len(4), vec![10, 9, 8, 7, 6, 5]);
+        assert_eq!(heap.len(), 4);
+        check_invariants(&heap);
+        assert!(heap.pop_until_len(7).is_empty());
+        assert_eq!(heap.pop_until_len(0), vec![4, 3, 2, 1]);
+    }
+
+    #[test]
+    fn batch_push_then_pop_n() {
+        let cases = [(vec![5, 1, 9], (10..20).collect::<Vec<_>>()), ((0..20).collect(), vec![25, -3, 30])];
+        for (existing, batch) in cases {
+            let mut fused = Heap::heapify(existing.clone());
+            let mut separate = Heap::heapify(existing);
+            separate.extend(batch.clone());
+            assert_eq!(fused.batch_push_then_pop_n(batch, 5), separate.pop_n(5));
+            check_invariants(&fused);
+            assert_eq!(fused.pop_ids(), separate.pop_ids());
+        }
+        let mut heap = Heap::heapify(vec![2, 1]);
+        assert_eq!(heap.batch_push_then_pop_n(vec![3], 10), vec![3, 2, 1]);
+    }
+
+    #[test]
+    fn root_id_changed_since() {
+        let mut heap = Heap::heapify(vec![]);
+        assert!(!heap.root_id_changed_since(None));
+        heap.push(5);
+        assert!(heap.root_id_changed_since(None));
+        let mut last = heap.peek_id();
+        heap.push(3);
+        assert!(!heap.root_id_changed_since(last));
+        heap.push(8);
+        assert!(heap.root_id_changed_since(last));
+        last = heap.peek_id();
+        heap.pop();
+        assert!(heap.root_id_changed_since(last));
+        last = heap.peek_id();
+        assert_eq!(last, Some(5));
+        heap.pop();
+        heap.pop();
+        assert!(heap.root_id_changed_since(last));
+        assert!(!heap.root_id_changed_since(None));
+    }
+
+    #[test]
+    fn sift_up() {
+        let mut heap = Heap::heapify(vec![1, 2, 3, 4, 5, 6, 7, 8, 9]);
+        let mut view = heap.get_mut_view();
+        view.sift_up();
+        check_invariants(&heap);
+    }
+
+    #[test]
     fn pushes() {
         let mut heap = Heap::heapify(vec![]);
         heap.push(10);
@@ -356,53 +3223,297 @@ mod tests {
     }
 
     #[test]
-    fn pops() {
-        let mut heap = Heap::heapify(vec![1, 2, 3, 4, 5, 6, 7, 8, 9]);
-        check_invariants(&heap);
-        while let Some(_) = heap.pop() {
+    fn pop_observer() {
+        use std::sync::Mutex;
+
+        let seen = Arc::new(Mutex::new(vec![]));
+        let mut heap = Heap::heapify(vec![0, 100, 9, 41, -10, 55]);
+        let recorder = Arc::clone(&seen);
+        heap.set_pop_observer(move |item: &i64| recorder.lock().unwrap().push(*item));
+        assert_eq!(heap.pop(), Some(100));
+        assert_eq!(heap.pop_if_root_id(55), Some(55));
+        assert_eq!(heap.drain_while(|t| t.key() > 5), vec![41, 9]);
+        heap.remove(-10);
+        let cloned = heap.clone();
+        assert_eq!(heap.drain_sorted().next(), Some(0));
+        assert_eq!(*seen.lock().unwrap(), vec![100, 55, 41, 9, 0]);
+
+        let mut cloned = cloned;
+        cloned.pop();
+        heap.clear_pop_observer();
+        heap.push(1);
+        heap.pop();
+        assert_eq!(seen.lock().unwrap().len(), 5);
+    }
+
+    #[test]
+    fn pop_observer_skips_discarded_drain() {
+        use std::sync::Mutex;
+
+        let seen = Arc::new(Mutex::new(vec![]));
+        let mut heap = Heap::heapify(vec![0, 100, 9, 41, -10, 55]);
+        let recorder = Arc::clone(&seen);
+        heap.set_pop_observer(move |item: &i64| recorder.lock().unwrap().push(*item));
+        let mut drain = heap.drain_sorted();
+        assert_eq!(drain.next(), Some(100));
+        assert_eq!(drain.next(), Some(55));
+        drop(drain);
+        assert!(heap.is_empty());
+        assert_eq!(*seen.lock().unwrap(), vec![100, 55]);
+    }
+
+    #[test]
+    fn pops() {
+        let mut heap = Heap::heapify(vec![1, 2, 3, 4, 5, 6, 7, 8, 9]);
+        check_invariants(&heap);
+        while heap.pop().is_some() {
+            check_invariants(&heap);
+        }
+    }
+
+    #[test]
+    fn heap_sort() {
+        let nums = vec![0, 100, 9, 41, -10, 55];
+        let mut expected = nums.clone();
+        expected.sort();
+        expected.reverse();
+        let mut heap = Heap::heapify(vec![]);
+        for num in nums {
+            heap.push(num);
+            check_invariants(&heap);
+        }
+        let mut result = vec![];
+        while let Some(num) = heap.pop() {
+            result.push(num);
+            check_invariants(&heap);
+        }
+        assert_eq!(expected, result);
+    }
+
+    #[test]
+    fn test_invariants_restored() {
+        let mut heap = Heap::heapify(vec![0, 100, 9, 41, -10, 55]);
+        println!("before first modification");
+        *heap.get_mut(-10).unwrap() = 200;
+        println!("before second modification");
+        *heap.get_mut(200).unwrap() = 300;
+        println!("after all modifications, before read");
+        assert_eq!(heap.heap[0], 300);
+        println!("after read");
+    }
+
+    #[cfg(feature = "stats")]
+    #[test]
+    fn read_only_guard_skips_sifting() {
+        let mut heap = Heap::heapify(vec![0, 100, 9, 41, -10, 55]);
+        heap.reset_stats();
+        assert_eq!(*heap.get_mut(9).unwrap(), 9);
+        assert_eq!(heap.stats(), Stats::default());
+        *heap.get_mut(9).unwrap() = 9;
+        assert_eq!(heap.stats(), Stats::default());
+        *heap.get_mut(9).unwrap() = 900;
+        assert!(heap.stats().swaps > 0);
+        check_invariants(&heap);
+    }
+
+    #[test]
+    fn commit_restores_once() {
+        let mut heap = Heap::heapify(vec![0, 100, 9, 41, -10, 55]);
+        let mut item = heap.get_mut(-10).unwrap();
+        *item = 200;
+        item.commit();
+        check_invariants(&heap);
+        assert_eq!(heap.peek(), Some(&200));
+        assert!(heap.get(-10).is_none());
+
+        let item = heap.get_mut(9).unwrap();
+        item.commit();
+        check_invariants(&heap);
+        assert_eq!(heap.len(), 6);
+    }
+
+    #[derive(Clone, Debug)]
+    struct Job{
+        priority: i64,
+        id: i64,
+        description: String
+    }
+
+    impl HeapItem for Job {
+        type Id = Id;
+
+        fn key(&self) -> Key {
+            self.priority
+        }
+
+        fn id(&self) -> Id {
+            self.id
+        }
+    }
+
+    impl SetKey for Job {
+        fn set_key(&mut self, key: Key) {
+            self.priority = key;
+        }
+    }
+
+    #[derive(Clone, Debug)]
+    struct NamedJob {
+        priority: i64,
+        name: String,
+    }
+
+    impl HeapItem for NamedJob {
+        type Id = String;
+
+        fn key(&self) -> Key {
+            self.priority
+        }
+
+        fn id(&self) -> String {
+            self.name.clone()
+        }
+    }
+
+    #[derive(Clone, Debug)]
+    struct Payload {
+        priority: i64,
+        handle: u32,
+    }
+
+    impl HeapItem for Payload {
+        type Id = Id;
+
+        fn key(&self) -> Key {
+            self.priority
+        }
+
+        fn id(&self) -> Id {
+            0
+        }
+    }
+
+    #[test]
+    fn with_id_fn() {
+        let mut heap = Heap::with_id_fn(|payload: &Payload| Id::from(payload.handle));
+        for handle in 1..=10 {
+            heap.push(Payload {priority: (i64::from(handle) * 7) % 10, handle});
             check_invariants(&heap);
         }
+        assert!(heap.index_map_consistent());
+        assert_eq!(heap.get(4).map(|payload| payload.handle), Some(4));
+        heap.get_mut(4).unwrap().priority = 100;
+        check_invariants(&heap);
+        assert_eq!(heap.peek_id(), Some(4));
+        assert_eq!(heap.remove(3).map(|payload| payload.handle), Some(3));
+        assert!(!heap.contains(3));
+        heap.stabilize_by_id();
+        let (id, payload) = heap.pop_with_id().unwrap();
+        assert_eq!((id, payload.handle), (4, 4));
+        assert_eq!(heap.clone().pop_ids().len(), 8);
+        assert!(heap.repair().is_clean());
     }
 
     #[test]
-    fn heap_sort() {
-        let nums = vec![0, 100, 9, 41, -10, 55];
-        let mut expected = nums.clone();
-        expected.sort();
-        expected.reverse();
-        let mut heap = Heap::heapify(vec![]);
-        for num in nums {
-            heap.push(num);
-            check_invariants(&heap);
+    fn with_id_fn_split_at_key() {
+        let mut heap = Heap::with_id_fn(|payload: &Payload| Id::from(payload.handle));
+        for handle in 1..=10 {
+            heap.push(Payload {priority: i64::from(handle), handle});
         }
-        let mut result = vec![];
-        while let Some(num) = heap.pop() {
-            result.push(num);
-            check_invariants(&heap);
+        let (mut high, mut low) = heap.split_at_key(6);
+        for half in [&high, &low] {
+            check_invariants(half);
+            assert!(half.index_map_consistent());
         }
-        assert_eq!(expected, result);
+        assert_eq!(low.get(3).map(|payload| payload.handle), Some(3));
+        assert!(high.get(3).is_none());
+        low.push(Payload {priority: 0, handle: 20});
+        high.push(Payload {priority: 50, handle: 21});
+        assert_eq!(low.pop_ids(), vec![5, 4, 3, 2, 1, 20]);
+        assert_eq!(high.pop_ids(), vec![21, 10, 9, 8, 7, 6]);
     }
 
     #[test]
-    fn test_invariants_restored() {
-        let mut heap = Heap::heapify(vec![0, 100, 9, 41, -10, 55]);
-        println!("before first modification");
-        *heap.get_mut(-10).unwrap() = 200;
-        println!("before second modification");
-        *heap.get_mut(200).unwrap() = 300;
-        println!("after all modifications, before read");
-        assert_eq!(heap.heap[0], 300);
-        println!("after read");
+    fn string_ids() {
+        let named = |name: &str, priority| NamedJob {priority, name: name.to_string()};
+        let mut job_queue = Heap::heapify(vec![named("backup", 5), named("email", 9), named("index", 3)]);
+        check_invariants(&job_queue);
+        assert_eq!(job_queue["index".to_string()].priority, 3);
+        job_queue.get_mut("index".to_string()).unwrap().priority = 20;
+        check_invariants(&job_queue);
+        assert_eq!(job_queue.peek_id().as_deref(), Some("index"));
+        assert_eq!(job_queue.remove("email".to_string()).map(|job| job.priority), Some(9));
+        assert!(job_queue.get("email".to_string()).is_none());
+        let (id, job) = job_queue.pop_with_id().unwrap();
+        assert_eq!((id.as_str(), job.priority), ("index", 20));
+
+        let duplicated = Heap::merge_sorted_runs(vec![vec![named("a", 1)], vec![named("a", 2)]]);
+        assert_eq!(duplicated.unwrap_err(), HeapError::DuplicateId("a".to_string()));
+    }
+
+    #[test]
+    fn change_key() {
+        let mut job_queue = Heap::heapify(vec![
+            Job {id: 1, priority: 100, description: "Very urgent!".to_string()},
+            Job {id: 2, priority: 50, description: "Medium urgent!".to_string()},
+            Job {id: 3, priority: 0, description: "Meh, whenever".to_string()}
+        ]);
+        assert!(job_queue.change_key(3, 200));
+        check_invariants(&job_queue);
+        assert!(job_queue.change_key(1, -1));
+        check_invariants(&job_queue);
+        assert!(!job_queue.change_key(4, 0));
+        let order: Vec<_> = std::iter::from_fn(|| job_queue.pop()).map(|job| job.id).collect();
+        assert_eq!(order, vec![3, 2, 1]);
+    }
+
+    #[test]
+    fn bulk_change_keys_by() {
+        let mut job_queue: Heap<Job> = (0..20)
+            .map(|id| Job {id, priority: (id * 7) % 20, description: String::new()})
+            .collect();
+        job_queue.bulk_change_keys_by(|job| if job.id % 2 == 0 { job.priority / 2 } else { -job.priority });
+        check_invariants(&job_queue);
+        let mut expected: Vec<_> = (0..20)
+            .map(|id: i64| if id % 2 == 0 { (id * 7) % 20 / 2 } else { -((id * 7) % 20) })
+            .collect();
+        expected.sort_unstable_by(|a, b| b.cmp(a));
+        let keys: Vec<_> = std::iter::from_fn(|| job_queue.pop()).map(|job| job.priority).collect();
+        assert_eq!(keys, expected);
+    }
+
+    impl SetId for Job {
+        fn set_id(&mut self, id: Id) {
+            self.id = id;
+        }
+    }
+
+    #[test]
+    fn compact_ids() {
+        let mut job_queue: Heap<Job> = (0..10)
+            .map(|n| Job {id: n * 100 + 7, priority: (n * 3) % 10, description: format!("job {}", n)})
+            .collect();
+        let remap = job_queue.compact_ids();
+        check_invariants(&job_queue);
+        assert_eq!(job_queue.id_set(), (0..10).collect());
+        assert_eq!(remap.len(), 10);
+        for n in 0..10 {
+            let job = &job_queue[remap[&(n * 100 + 7)]];
+            assert_eq!(job.description, format!("job {}", n));
+        }
+        assert_eq!(job_queue.pop().unwrap().description, "job 3");
     }
 
     #[derive(Clone, Debug)]
-    struct Job{
+    struct Renumbered {
         priority: i64,
-        id: i64,
-        description: String
+        id: Id,
+        handle: Id,
     }
 
-    impl HeapItem for Job {
+    impl HeapItem for Renumbered {
+        type Id = Id;
+
         fn key(&self) -> Key {
             self.priority
         }
@@ -412,6 +3523,179 @@ mod tests {
         }
     }
 
+    impl SetId for Renumbered {
+        fn set_id(&mut self, id: Id) {
+            self.id = id;
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "compact_ids can't renumber ids derived by an id function")]
+    fn compact_ids_rejects_id_fn() {
+        let mut heap = Heap::with_id_fn(|item: &Renumbered| item.handle);
+        heap.push(Renumbered {priority: 1, id: 50, handle: 7});
+        heap.compact_ids();
+    }
+
+    #[test]
+    fn pop_ids_and_peek_n_ids() {
+        let mut job_queue: Heap<Job> = (0..10)
+            .map(|id| Job {id, priority: (id * 3) % 10, description: String::new()})
+            .collect();
+        assert_eq!(job_queue.peek_n_ids(3), vec![3, 6, 9]);
+        assert_eq!(job_queue.peek_n_ids(20).len(), 10);
+        assert_eq!(job_queue.len(), 10);
+        assert_eq!(job_queue.pop_ids(), vec![3, 6, 9, 2, 5, 8, 1, 4, 7, 0]);
+        assert!(job_queue.is_empty());
+        assert!(job_queue.peek_n_ids(1).is_empty());
+    }
+
+    #[test]
+    fn stabilize_by_id() {
+        let mut job_queue: Heap<Job> = [9, 2, 7, 4, 1, 8, 3]
+            .iter()
+            .map(|&id| Job {id, priority: if id % 2 == 0 { 5 } else { 1 }, description: String::new()})
+            .collect();
+        job_queue.stabilize_by_id();
+        check_invariants(&job_queue);
+        job_queue.push(Job {id: 0, priority: 5, description: String::new()});
+        assert_eq!(job_queue.pop_ids(), vec![0, 2, 4, 8, 1, 3, 7, 9]);
+    }
+
+    #[derive(Clone, Debug)]
+    struct Borrowed<'a> {
+        priority: i64,
+        name: &'a str,
+    }
+
+    impl<'a> HeapItem for Borrowed<'a> {
+        type Id = &'a str;
+
+        fn key(&self) -> Key {
+            self.priority
+        }
+
+        fn id(&self) -> &'a str {
+            self.name
+        }
+    }
+
+    #[test]
+    fn stabilize_by_id_borrowed_items() {
+        let names: Vec<String> = ["d", "b", "c", "a"].iter().map(|name| name.to_string()).collect();
+        let mut heap: Heap<Borrowed<'_>> = names.iter().map(|name| Borrowed {priority: 1, name}).collect();
+        heap.stabilize_by_id();
+        check_invariants(&heap);
+        assert_eq!(heap.pop_ids(), vec!["a", "b", "c", "d"]);
+    }
+
+    #[test]
+    fn replace_all() {
+        let mut job_queue: Heap<Job> = (0..10)
+            .map(|id| Job {id, priority: id, description: format!("job {}", id)})
+            .collect();
+        job_queue.replace_all(|job| Job {
+            priority: -job.priority,
+            description: job.description.to_uppercase(),
+            ..job
+        });
+        check_invariants(&job_queue);
+        assert_eq!(job_queue[4].description, "JOB 4");
+        assert_eq!(job_queue.pop_ids(), (0..10).collect::<Vec<_>>());
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "replace_all must preserve ids")]
+    fn replace_all_checks_ids() {
+        let mut heap = Heap::heapify(vec![1, 2, 3]);
+        heap.replace_all(|x| x * 10);
+    }
+
+    #[test]
+    fn swap_root_with_leaf_min() {
+        let mut job_queue: Heap<Job> = (1..=6)
+            .map(|id| Job {id, priority: id * 10, description: String::new()})
+            .collect();
+        let (demoted, promoted) = job_queue.swap_root_with_leaf_min().unwrap();
+        assert_eq!((demoted.id, demoted.priority), (6, 10));
+        assert_eq!((promoted.id, promoted.priority), (1, 60));
+        check_invariants(&job_queue);
+        assert_eq!(job_queue.pop_ids(), vec![1, 5, 4, 3, 2, 6]);
+
+        let mut single = Heap::singleton(Job {id: 1, priority: 1, description: String::new()});
+        assert!(single.swap_root_with_leaf_min().is_none());
+    }
+
+    #[test]
+    fn swap_payloads_keep_keys() {
+        let mut job_queue = Heap::heapify(vec![
+            Job {id: 1, priority: 100, description: "Very urgent!".to_string()},
+            Job {id: 2, priority: 50, description: "Medium urgent!".to_string()},
+            Job {id: 3, priority: 0, description: "Meh, whenever".to_string()}
+        ]);
+        assert!(job_queue.swap_payloads_keep_keys(1, 3));
+        check_invariants(&job_queue);
+        assert_eq!(job_queue[3].priority, 100);
+        assert_eq!(job_queue[3].description, "Meh, whenever");
+        assert_eq!(job_queue[1].priority, 0);
+        assert_eq!(job_queue[1].description, "Very urgent!");
+        assert!(!job_queue.swap_payloads_keep_keys(2, 2));
+        assert!(!job_queue.swap_payloads_keep_keys(2, 4));
+        let order: Vec<_> = std::iter::from_fn(|| job_queue.pop()).map(|job| job.id).collect();
+        assert_eq!(order, vec![3, 2, 1]);
+    }
+
+    #[test]
+    fn dedup_by_id() {
+        let jobs = [(1, 5), (2, 9), (1, 7), (3, 1), (2, 4), (1, 6)];
+        let build = || -> Heap<Job> {
+            Heap::heapify(jobs.iter().map(|&(id, priority)| Job {id, priority, description: String::new()}).collect())
+        };
+        let mut first = build();
+        assert!(!first.index_map_consistent());
+        assert_eq!(first.dedup_by_id(DedupKeep::First), 3);
+        check_invariants(&first);
+        assert!(first.index_map_consistent());
+        let mut highest = build();
+        assert_eq!(highest.dedup_by_id(DedupKeep::HighestKey), 3);
+        check_invariants(&highest);
+        assert_eq!((highest[1].priority, highest[2].priority, highest[3].priority), (7, 9, 1));
+        assert_eq!(highest.dedup_by_id(DedupKeep::First), 0);
+        assert_eq!(first.len(), 3);
+    }
+
+    #[test]
+    fn coalesce() {
+        let mut job_queue: Heap<Job> = [(1, 5, "backup"), (2, 9, "email"), (3, 7, "backup"), (4, 1, "email"), (5, 3, "index")]
+            .iter()
+            .map(|&(id, priority, description)| Job {id, priority, description: description.to_string()})
+            .collect();
+        job_queue.coalesce(
+            |a, b| a.description == b.description,
+            |a, b| if a.priority >= b.priority { a } else { b },
+        );
+        check_invariants(&job_queue);
+        assert_eq!(job_queue.len(), 3);
+        assert!(job_queue.get(1).is_none() && job_queue.get(4).is_none());
+        let order: Vec<_> = std::iter::from_fn(|| job_queue.pop()).map(|job| (job.id, job.description)).collect();
+        assert_eq!(order, vec![(2, "email".to_string()), (3, "backup".to_string()), (5, "index".to_string())]);
+    }
+
+    #[test]
+    fn into_sorted_vec_by() {
+        let jobs = (0..10).map(|id| Job {id, priority: (id * 3) % 10, description: format!("job {}", id)});
+        let job_queue: Heap<Job> = jobs.collect();
+        let by_id: Vec<_> = job_queue.clone().into_sorted_vec_by(|a, b| a.id.cmp(&b.id)).iter().map(|job| job.id).collect();
+        assert_eq!(by_id, (0..10).collect::<Vec<_>>());
+        let by_parity: Vec<_> = job_queue
+            .into_sorted_vec_by(|a, b| (a.id % 2).cmp(&(b.id % 2)))
+            .iter()
+            .map(|job| job.priority)
+            .collect();
+        assert_eq!(by_parity, vec![8, 6, 4, 2, 0, 9, 7, 5, 3, 1]);
+    }
+
     #[test]
     fn test_invariants_restored_automatically() {
         let mut job_queue = Heap::heapify(vec![
@@ -429,4 +3713,309 @@ mod tests {
         assert_eq!(&job_queue.pop().unwrap().description, "The boss wants this yesterday!");
         println!("after read");
     }
+
+    #[test]
+    fn peek_min_mut() {
+        let mut job_queue: Heap<Job> = (1..=7)
+            .map(|id| Job {id, priority: id * 10, description: String::new()})
+            .collect();
+        {
+            let mut min = job_queue.peek_min_mut().unwrap();
+            assert_eq!(min.id, 1);
+            min.priority = 65;
+        }
+        check_invariants(&job_queue);
+        assert_eq!(job_queue.find_min_id(), Some(2));
+        job_queue.peek_min_mut().unwrap().priority = 100;
+        check_invariants(&job_queue);
+        assert_eq!(job_queue.pop_ids(), vec![2, 7, 1, 6, 5, 4, 3]);
+        assert!(job_queue.peek_min_mut().is_none());
+    }
+
+    #[test]
+    fn replace_by_id_same_id() {
+        let mut job_queue = Heap::heapify(vec![
+            Job {id: 1, priority: 100, description: "Very urgent!".to_string()},
+            Job {id: 2, priority: 50, description: "Medium urgent!".to_string()},
+            Job {id: 3, priority: 0, description: "Meh, whenever".to_string()}
+        ]);
+        let old = job_queue.replace_by_id(3, Job {id: 3, priority: 200, description: "Now!".to_string()});
+        assert_eq!(old.unwrap().description, "Meh, whenever");
+        check_invariants(&job_queue);
+        assert_eq!(job_queue.peek().unwrap().id, 3);
+        assert!(job_queue.replace_by_id(4, Job {id: 4, priority: 0, description: String::new()}).is_none());
+        assert_eq!(job_queue.len(), 3);
+    }
+
+    #[test]
+    fn replace_by_id_new_id() {
+        let mut heap = Heap::heapify(vec![0, 100, 9, 41, -10, 55]);
+        assert_eq!(heap.replace_by_id(100, 1), Some(100));
+        check_invariants(&heap);
+        assert!(heap.get(100).is_none());
+        assert_eq!(heap.get(1), Some(&1));
+        assert_eq!(heap.replace_by_id(-10, 70), Some(-10));
+        check_invariants(&heap);
+        assert_eq!(heap.peek(), Some(&70));
+    }
+
+    #[test]
+    #[should_panic(expected = "replacement id 41 already belongs to another element")]
+    fn replace_by_id_rejects_taken_id() {
+        let mut heap = Heap::heapify(vec![0, 100, 9, 41, -10, 55]);
+        heap.replace_by_id(9, 41);
+    }
+
+    #[test]
+    fn get_pair_mut() {
+        let mut job_queue = Heap::heapify(vec![
+            Job {id: 1, priority: 100, description: "Parent".to_string()},
+            Job {id: 2, priority: 50, description: "Child".to_string()}
+        ]);
+        assert!(job_queue.get_pair_mut(1, 1).is_none());
+        assert!(job_queue.get_pair_mut(1, 5).is_none());
+
+        let choices = [None, Some(-1), Some(1), Some(3), Some(5), Some(7), Some(9), Some(20)];
+        for len in 2..=7 {
+            let job_queue: Heap<Job> = (0..len)
+                .map(|id| Job {id, priority: 2 * (len - id), description: String::new()})
+                .collect();
+            for a in 0..len {
+                for b in (0..len).filter(|&b| b != a) {
+                    for new_a in choices {
+                        for new_b in choices {
+                            let mut edited = job_queue.clone();
+                            {
+                                let mut guard = edited.get_pair_mut(a, b).unwrap();
+                                let (first, second) = guard.pair_mut();
+                                assert_eq!((first.id, second.id), (a, b));
+                                first.priority = new_a.unwrap_or(first.priority);
+                                second.priority = new_b.unwrap_or(second.priority);
+                            }
+                            check_invariants(&edited);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn pop_if_root_id() {
+        let mut job_queue = Heap::heapify(vec![
+            Job {id: 1, priority: 100, description: "Very urgent!".to_string()},
+            Job {id: 2, priority: 50, description: "Medium urgent!".to_string()}
+        ]);
+        assert_eq!(job_queue.peek_id(), Some(1));
+        job_queue.get_mut(2).unwrap().priority = 150;
+        assert!(job_queue.pop_if_root_id(1).is_none());
+        assert_eq!(job_queue.len(), 2);
+        assert_eq!(job_queue.pop_if_root_id(2).unwrap().id, 2);
+        assert_eq!(job_queue.pop_if_root_id(1).unwrap().id, 1);
+        assert!(job_queue.pop_if_root_id(1).is_none());
+    }
+
+    #[derive(Clone, Debug)]
+    struct SharedPriority {
+        id: Id,
+        priority: std::rc::Rc<std::cell::Cell<Key>>,
+    }
+
+    impl HeapItem for SharedPriority {
+        type Id = Id;
+
+        fn key(&self) -> Key {
+            self.priority.get()
+        }
+
+        fn id(&self) -> Id {
+            self.id
+        }
+    }
+
+    #[derive(Clone, Debug)]
+    struct Tracked {
+        key: i64,
+        position: usize,
+        moves: usize,
+    }
+
+    impl HeapItem for Tracked {
+        type Id = Id;
+
+        fn key(&self) -> Key {
+            self.key
+        }
+
+        fn id(&self) -> Id {
+            self.key
+        }
+
+        fn on_move(&mut self, new_index: usize) {
+            self.position = new_index;
+            self.moves += 1;
+        }
+    }
+
+    #[test]
+    fn on_move_tracks_positions() {
+        let tracked = |key| Tracked {key, position: usize::MAX, moves: 0};
+        let check_positions = |heap: &Heap<Tracked>| {
+            check_invariants(heap);
+            for (index, item) in heap.heap.iter().enumerate() {
+                assert_eq!(item.position, index, "{:?}", item);
+            }
+        };
+        let mut heap = Heap::heapify((0..20).map(|x| tracked((x * 7) % 20)).collect());
+        check_positions(&heap);
+        for key in 20..30 {
+            heap.push(tracked(key));
+            check_positions(&heap);
+        }
+        assert!(heap.heap.iter().any(|item| item.moves > 1));
+        heap.pop();
+        heap.remove(3);
+        heap.replace_root(tracked(-1));
+        check_positions(&heap);
+        heap.replace_by_id(5, tracked(35));
+        heap.get_pair_mut(10, 12).unwrap().pair_mut().0.key = 40;
+        check_positions(&heap);
+        heap.retain_ids(|id| id % 3 != 0);
+        heap.extend((50..90).map(tracked));
+        check_positions(&heap);
+        let mut cursor = Heap::merge_sorted_runs(vec![vec![tracked(1), tracked(2)]]).unwrap();
+        check_positions(&cursor);
+        cursor.pop_min();
+        check_positions(&cursor);
+    }
+
+    #[test]
+    fn reorder() {
+        let items: Vec<_> = (0..10)
+            .map(|id| SharedPriority { id, priority: std::rc::Rc::new(std::cell::Cell::new(id * 10)) })
+            .collect();
+        let handles: Vec<_> = items.iter().map(|item| item.priority.clone()).collect();
+        let mut heap = Heap::heapify(items);
+        handles[2].set(1000);
+        assert!(heap.reorder(2));
+        check_invariants(&heap);
+        assert_eq!(heap.peek_id(), Some(2));
+        handles[2].set(-5);
+        assert!(heap.reorder(2));
+        check_invariants(&heap);
+        assert_eq!(heap.peek_id(), Some(9));
+        assert!(!heap.reorder(9));
+        assert!(!heap.reorder(100));
+    }
+
+    #[test]
+    fn compare_and_pop() {
+        let mut heap = Heap::heapify(vec![0, 100, 9, 41]);
+        let observed = heap.peek_key().unwrap();
+        assert_eq!(heap.compare_and_pop(observed), Some(100));
+        assert_eq!(heap.compare_and_pop(observed), None);
+        assert_eq!(heap.len(), 3);
+        assert_eq!(heap.compare_and_pop(41), Some(41));
+        assert_eq!(Heap::<i64>::heapify(vec![]).compare_and_pop(0), None);
+    }
+
+    #[test]
+    fn reinsert_popped() {
+        let mut heap = Heap::heapify((0..50).map(|x| (x * 37) % 101).collect());
+        for round in 0..20 {
+            let top = heap.pop().unwrap();
+            if round % 3 == 0 {
+                heap.pop();
+            }
+            heap.reinsert_popped(top);
+            check_invariants(&heap);
+            assert_eq!(heap.peek(), Some(&top));
+        }
+        heap.reinsert_popped(-1);
+        check_invariants(&heap);
+        let mut empty = Heap::heapify(vec![]);
+        empty.reinsert_popped(3);
+        assert_eq!(empty.pop(), Some(3));
+    }
+
+    #[test]
+    fn pop_with_id() {
+        let mut job_queue = Heap::heapify(vec![
+            Job {id: 1, priority: 0, description: "Meh, whenever".to_string()},
+            Job {id: 2, priority: 100, description: "Very urgent!".to_string()},
+            Job {id: 3, priority: 50, description: "Medium urgent!".to_string()}
+        ]);
+        let mut popped_ids = vec![];
+        while let Some((id, job)) = job_queue.pop_with_id() {
+            assert_eq!(id, job.id());
+            assert!(job_queue.get(id).is_none());
+            popped_ids.push(id);
+            check_invariants(&job_queue);
+        }
+        assert_eq!(popped_ids, vec![2, 3, 1]);
+    }
+
+    #[cfg(feature = "tracing")]
+    mod tracing_spans {
+        use std::sync::{Arc, Mutex};
+        use tracing::field::{Field, Visit};
+        use tracing::span::{Attributes, Record};
+        use tracing::{Event, Metadata, Subscriber};
+        use super::*;
+
+        #[derive(Default)]
+        struct SiftSteps(Option<u64>);
+
+        impl Visit for SiftSteps {
+            fn record_u64(&mut self, field: &Field, value: u64) {
+                if field.name() == "sift_steps" {
+                    self.0 = Some(value);
+                }
+            }
+
+            fn record_debug(&mut self, _field: &Field, _value: &dyn Debug) {}
+        }
+
+        type RecordedSpans = Arc<Mutex<Vec<(&'static str, Option<u64>)>>>;
+
+        #[derive(Default)]
+        struct SpanRecorder {
+            spans: RecordedSpans,
+        }
+
+        impl Subscriber for SpanRecorder {
+            fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+                true
+            }
+
+            fn new_span(&self, span: &Attributes<'_>) -> tracing::span::Id {
+                let mut spans = self.spans.lock().unwrap();
+                spans.push((span.metadata().name(), None));
+                tracing::span::Id::from_u64(spans.len() as u64)
+            }
+
+            fn record(&self, span: &tracing::span::Id, values: &Record<'_>) {
+                let mut steps = SiftSteps::default();
+                values.record(&mut steps);
+                self.spans.lock().unwrap()[span.into_u64() as usize - 1].1 = steps.0;
+            }
+
+            fn record_follows_from(&self, _span: &tracing::span::Id, _follows: &tracing::span::Id) {}
+
+            fn event(&self, _event: &Event<'_>) {}
+
+            fn enter(&self, _span: &tracing::span::Id) {}
+
+            fn exit(&self, _span: &tracing::span::Id) {}
+        }
+
+        #[test]
+        fn push_emits_span() {
+            let recorder = SpanRecorder::default();
+            let spans = recorder.spans.clone();
+            let mut heap = Heap::heapify(vec![1, 2, 3, 4, 5, 6, 7]);
+            tracing::subscriber::with_default(recorder, || heap.push(100));
+            assert_eq!(*spans.lock().unwrap(), vec![("push", Some(3))]);
+        }
+    }
 }
@@ -1,14 +1,17 @@
-// use pyo3::{Py, PyAny};
-
+mod error;
 mod heap;
+#[cfg(feature = "python")]
+mod python;
+#[cfg(feature = "stats")]
+mod stats;
+mod trace;
 
-pub use heap::{Heap, HeapItem};
+pub use error::HeapError;
+#[cfg(feature = "python")]
+pub use python::PyHeap;
+#[cfg(feature = "stats")]
+pub use stats::Stats;
+pub use heap::{heapify_in_place, is_heap, CapacityPolicy, DedupKeep, DrainSorted, Heap, HeapItem, RepairReport, SetId, SetKey};
 
 type Key = i64;
 type Id = i64;
-
-struct PyWrapper {
-    py_id: Id,
-    key: Key,
-    // object: Py<PyAny>
-}
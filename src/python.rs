@@ -0,0 +1,233 @@
+use std::sync::Arc;
+use pyo3::exceptions::{PyKeyError, PyValueError};
+use pyo3::prelude::*;
+use crate::{Heap, HeapItem, Id, Key, SetKey};
+
+#[derive(Debug, Clone)]
+struct PyWrapper {
+    py_id: Id,
+    key: Key,
+    object: Arc<Py<PyAny>>,
+}
+
+impl HeapItem for PyWrapper {
+    type Id = Id;
+
+    fn key(&self) -> Key {
+        self.key
+    }
+
+    fn id(&self) -> Id {
+        self.py_id
+    }
+}
+
+impl SetKey for PyWrapper {
+    fn set_key(&mut self, key: Key) {
+        self.key = key;
+    }
+}
+
+#[pyclass]
+pub struct PyHeap {
+    heap: Heap<PyWrapper>,
+    next_id: Id,
+}
+
+#[pymethods]
+impl PyHeap {
+    #[new]
+    fn new() -> Self {
+        PyHeap {
+            heap: Heap::heapify(vec![]),
+            next_id: 0,
+        }
+    }
+
+    fn __len__(&self) -> usize {
+        self.heap.len()
+    }
+
+    fn __contains__(&self, py_id: Id) -> bool {
+        self.heap.contains(py_id)
+    }
+
+    /// Pushes `object` with priority `key` and returns the id it is stored under. Unless
+    /// `py_id` is given, ids are assigned from an incrementing counter, skipping any already
+    /// taken by explicit ids.
+    #[pyo3(signature = (object, key, py_id = None))]
+    fn push(&mut self, object: Py<PyAny>, key: Key, py_id: Option<Id>) -> PyResult<Id> {
+        let py_id = match py_id {
+            Some(py_id) if self.heap.get(py_id).is_some() => {
+                return Err(PyValueError::new_err(format!("duplicate id {}", py_id)));
+            }
+            Some(py_id) => py_id,
+            None => {
+                while self.heap.get(self.next_id).is_some() {
+                    self.next_id += 1;
+                }
+                self.next_id += 1;
+                self.next_id - 1
+            }
+        };
+        self.heap.push(PyWrapper {
+            py_id,
+            key,
+            object: Arc::new(object),
+        });
+        Ok(py_id)
+    }
+
+    fn pop(&mut self, py: Python<'_>) -> Option<Py<PyAny>> {
+        self.heap.pop().map(|wrapper| wrapper.object.clone_ref(py))
+    }
+
+    fn peek(&self, py: Python<'_>) -> Option<Py<PyAny>> {
+        self.heap.peek().map(|wrapper| wrapper.object.clone_ref(py))
+    }
+
+    /// Removes the object stored under `py_id`, the handle returned by `push`, and returns it,
+    /// or `None` if no such handle is present.
+    fn remove(&mut self, py: Python<'_>, py_id: Id) -> Option<Py<PyAny>> {
+        self.heap.remove(py_id).map(|wrapper| wrapper.object.clone_ref(py))
+    }
+
+    fn peek_with_key(&self, py: Python<'_>) -> Option<(Py<PyAny>, Key)> {
+        self.heap.peek().map(|wrapper| (wrapper.object.clone_ref(py), wrapper.key))
+    }
+
+    fn change_priority(&mut self, py_id: Id, new_key: Key) -> PyResult<()> {
+        if self.heap.change_key(py_id, new_key) {
+            Ok(())
+        } else {
+            Err(PyKeyError::new_err(py_id))
+        }
+    }
+}
+
+#[pymodule]
+fn trapper_keeper(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyHeap>()
+}
+
+#[cfg(test)]
+mod tests {
+    use pyo3::ffi::c_str;
+    use pyo3::types::{PyDict, PyString};
+    use super::*;
+
+    fn pop_str(heap: &mut PyHeap, py: Python<'_>) -> Option<String> {
+        heap.pop(py).map(|object| object.extract(py).unwrap())
+    }
+
+    #[test]
+    fn from_python() {
+        Python::initialize();
+        Python::attach(|py| {
+            let locals = PyDict::new(py);
+            locals.set_item("h", Bound::new(py, PyHeap::new()).unwrap()).unwrap();
+            let script = c_str!(
+                r#"
+first = h.push("a", 1)
+assert isinstance(first, int)
+assert first in h
+assert h.remove(first) == "a"
+assert first not in h and 0 not in h
+assert h.remove(first) is None
+
+assert h.push("b", 5, py_id=7) == 7
+try:
+    h.push("c", 6, py_id=7)
+except ValueError:
+    pass
+else:
+    raise AssertionError("duplicate id accepted")
+try:
+    h.change_priority(3, 1)
+except KeyError:
+    pass
+else:
+    raise AssertionError("missing id accepted")
+
+h.push("d", 0)
+h.change_priority(7, -1)
+assert h.peek_with_key() == ("d", 0)
+assert len(h) == 2
+assert h.pop() == "d" and h.pop() == "b" and h.pop() is None
+"#
+            );
+            if let Err(err) = py.run(script, None, Some(&locals)) {
+                panic!("{}", err);
+            }
+        });
+    }
+
+    #[test]
+    fn remove() {
+        Python::initialize();
+        Python::attach(|py| {
+            let mut heap = PyHeap::new();
+            let first = heap.push(PyString::new(py, "first").into_any().unbind(), 1, None).unwrap();
+            heap.push(PyString::new(py, "second").into_any().unbind(), 2, None).unwrap();
+            let removed = heap.remove(py, first).unwrap();
+            assert_eq!(removed.extract::<String>(py).unwrap(), "first");
+            assert!(heap.remove(py, first).is_none());
+            assert!(!heap.__contains__(first));
+            assert_eq!(heap.__len__(), 1);
+        });
+    }
+
+    #[test]
+    fn change_priority() {
+        Python::initialize();
+        Python::attach(|py| {
+            let mut heap = PyHeap::new();
+            let low = heap.push(PyString::new(py, "low").into_any().unbind(), 1, None).unwrap();
+            let high = heap.push(PyString::new(py, "high").into_any().unbind(), 10, None).unwrap();
+            assert_eq!((low, high), (0, 1));
+            assert_eq!(heap.push(PyString::new(py, "explicit").into_any().unbind(), 5, Some(2)).unwrap(), 2);
+            assert!(heap.push(PyString::new(py, "dup").into_any().unbind(), 5, Some(2)).is_err());
+            assert_eq!(heap.push(PyString::new(py, "auto").into_any().unbind(), 0, None).unwrap(), 3);
+
+            heap.change_priority(low, 100).unwrap();
+            assert!(heap.change_priority(42, 0).is_err());
+            assert_eq!(heap.__len__(), 4);
+            let (top, key) = heap.peek_with_key(py).unwrap();
+            assert_eq!((top.extract::<String>(py).unwrap().as_str(), key), ("low", 100));
+            assert!(heap.__contains__(high) && !heap.__contains__(42));
+            assert_eq!(pop_str(&mut heap, py).as_deref(), Some("low"));
+            assert_eq!(pop_str(&mut heap, py).as_deref(), Some("high"));
+            assert_eq!(pop_str(&mut heap, py).as_deref(), Some("explicit"));
+            assert_eq!(pop_str(&mut heap, py).as_deref(), Some("auto"));
+            assert_eq!(pop_str(&mut heap, py), None);
+        });
+    }
+
+    #[test]
+    fn exception_types() {
+        Python::initialize();
+        Python::attach(|py| {
+            let mut heap = PyHeap::new();
+            assert_eq!(heap.push(PyString::new(py, "a").into_any().unbind(), 5, Some(7)).unwrap(), 7);
+            let duplicate = heap.push(PyString::new(py, "b").into_any().unbind(), 6, Some(7)).unwrap_err();
+            assert!(duplicate.is_instance_of::<PyValueError>(py));
+            let missing = heap.change_priority(3, 1).unwrap_err();
+            assert!(missing.is_instance_of::<PyKeyError>(py));
+            assert_eq!(heap.__len__(), 1);
+        });
+    }
+
+    #[test]
+    fn peek_with_key() {
+        Python::initialize();
+        Python::attach(|py| {
+            let mut heap = PyHeap::new();
+            assert!(heap.peek_with_key(py).is_none());
+            heap.push(PyString::new(py, "low").into_any().unbind(), 1, None).unwrap();
+            heap.push(PyString::new(py, "high").into_any().unbind(), 10, None).unwrap();
+            let (top, key) = heap.peek_with_key(py).unwrap();
+            assert_eq!((top.extract::<String>(py).unwrap().as_str(), key), ("high", 10));
+            assert_eq!(heap.__len__(), 2);
+        });
+    }
+}
@@ -0,0 +1,45 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    pub comparisons: usize,
+    pub swaps: usize,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct StatCounters {
+    comparisons: AtomicUsize,
+    swaps: AtomicUsize,
+}
+
+impl StatCounters {
+    pub(crate) fn count_comparison(&self) {
+        self.comparisons.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn count_swap(&self) {
+        self.swaps.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> Stats {
+        Stats {
+            comparisons: self.comparisons.load(Ordering::Relaxed),
+            swaps: self.swaps.load(Ordering::Relaxed),
+        }
+    }
+
+    pub(crate) fn reset(&self) {
+        self.comparisons.store(0, Ordering::Relaxed);
+        self.swaps.store(0, Ordering::Relaxed);
+    }
+}
+
+impl Clone for StatCounters {
+    fn clone(&self) -> Self {
+        let stats = self.snapshot();
+        StatCounters {
+            comparisons: AtomicUsize::new(stats.comparisons),
+            swaps: AtomicUsize::new(stats.swaps),
+        }
+    }
+}
@@ -0,0 +1,34 @@
+#[cfg(feature = "tracing")]
+macro_rules! op_span {
+    ($name:literal, $len:expr) => {
+        $crate::trace::OpSpan(
+            tracing::trace_span!($name, len = $len, sift_steps = tracing::field::Empty).entered(),
+        )
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! op_span {
+    ($name:literal, $len:expr) => {
+        $crate::trace::OpSpan
+    };
+}
+
+pub(crate) use op_span;
+
+#[cfg(feature = "tracing")]
+pub(crate) struct OpSpan(pub(crate) tracing::span::EnteredSpan);
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) struct OpSpan;
+
+impl OpSpan {
+    #[cfg(feature = "tracing")]
+    pub(crate) fn record_sift_steps(&self, steps: usize) {
+        self.0.record("sift_steps", steps);
+    }
+
+    #[cfg(not(feature = "tracing"))]
+    #[inline(always)]
+    pub(crate) fn record_sift_steps(&self, _steps: usize) {}
+}